@@ -0,0 +1,44 @@
+use crate::config::{AwsClientConfig, RetryMode};
+use aws_config::retry::RetryConfig;
+use aws_config::timeout::TimeoutConfig;
+use aws_config::{BehaviorVersion, ConfigLoader};
+use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
+
+/// Returns a loader for the SDK config shared by the S3 and STS clients.
+pub fn config_loader(client_config: &AwsClientConfig) -> ConfigLoader {
+    let mut timeout_config = TimeoutConfig::builder();
+    timeout_config.set_connect_timeout(client_config.connect_timeout);
+    timeout_config.set_read_timeout(client_config.read_timeout);
+    timeout_config.set_operation_timeout(client_config.operation_timeout);
+
+    let retry_config = match client_config.retry_mode {
+        RetryMode::Standard => RetryConfig::standard(),
+        RetryMode::Adaptive => RetryConfig::adaptive(),
+    };
+    let retry_config = match client_config.max_attempts {
+        Some(max_attempts) => retry_config.with_max_attempts(max_attempts),
+        None => retry_config,
+    };
+
+    let loader = aws_config::defaults(BehaviorVersion::latest())
+        .timeout_config(timeout_config.build())
+        .retry_config(retry_config);
+
+    if client_config.pool_max_idle_per_host.is_none() && client_config.pool_idle_timeout.is_none() {
+        return loader;
+    }
+
+    let mut hyper_builder = hyper_0_14::Client::builder();
+    if let Some(max_idle) = client_config.pool_max_idle_per_host {
+        hyper_builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = client_config.pool_idle_timeout {
+        hyper_builder.pool_idle_timeout(idle_timeout);
+    }
+
+    loader.http_client(
+        HyperClientBuilder::new()
+            .hyper_builder(hyper_builder)
+            .build_https(),
+    )
+}
@@ -0,0 +1,93 @@
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+/// Upper bound of cached buckets per site, as wildcard domains allow any number of hosts.
+const MAX_CACHED_BUCKETS: usize = 10_000;
+
+/// Values read per bucket of a site. Beyond the bound, the least recently used bucket is dropped.
+#[derive(Debug)]
+pub struct BucketCache<V> {
+    entries: Mutex<LruCache<String, V>>,
+}
+
+impl<V: Clone> BucketCache<V> {
+    pub fn new() -> Self {
+        Self::with_capacity(MAX_CACHED_BUCKETS)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn get(&self, bucket: &str) -> Option<V> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(bucket)
+            .cloned()
+    }
+
+    /// The value of the bucket, inserting the one of `init` when there is none.
+    pub fn get_or_insert_with<F>(&self, bucket: &str, init: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert(bucket.to_string(), init)
+            .clone()
+    }
+
+    pub fn insert(&self, bucket: &str, value: V) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .put(bucket.to_string(), value);
+    }
+
+    pub fn remove(&self, bucket: &str) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop(bucket);
+    }
+
+    pub fn buckets(&self) -> Vec<String> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(bucket, _)| bucket.clone())
+            .collect()
+    }
+}
+
+impl<V: Clone> Default for BucketCache<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_recently_used_dropped() {
+        let cache = BucketCache::with_capacity(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get("a"), Some(1));
+        cache.insert("c", 3);
+
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get_or_insert_with("a", || 0), 1);
+        assert_eq!(cache.get_or_insert_with("d", || 4), 4);
+        assert_eq!(cache.get("c"), None);
+    }
+}
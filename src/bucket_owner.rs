@@ -0,0 +1,235 @@
+use crate::bucket_cache::BucketCache;
+use crate::config::BucketOwnerCacheConfig;
+use crate::metrics;
+use crate::storage::{Storage, StorageError};
+use std::time::{Duration, Instant};
+
+/// Checks that buckets belong to one of `owners`, reusing HeadBucket results until their TTL passes.
+/// Transient failures such as throttling are not cached.
+#[derive(Debug)]
+pub struct BucketOwnerCheck {
+    owners: Vec<String>,
+    cache: BucketOwnerCacheConfig,
+    verdicts: BucketCache<Verdict>,
+}
+
+#[derive(Debug, Clone)]
+struct Verdict {
+    result: Result<String, StorageError>,
+    expires_at: Instant,
+}
+
+impl BucketOwnerCheck {
+    pub fn new(owners: Vec<String>, cache: BucketOwnerCacheConfig) -> Self {
+        Self {
+            owners,
+            cache,
+            verdicts: BucketCache::new(),
+        }
+    }
+
+    /// Returns the owner of the bucket, which is passed as the expected owner of later S3 requests.
+    pub async fn check<T>(&self, storage: &T, bucket: &str) -> Result<String, StorageError>
+    where
+        T: Storage + Send + Sync + ?Sized,
+    {
+        if let Some(result) = self.cached(bucket) {
+            metrics::BUCKET_OWNER_CACHE_HITS.inc();
+            return result;
+        }
+        metrics::BUCKET_OWNER_CACHE_MISSES.inc();
+
+        let result = self.head_bucket(storage, bucket).await;
+        let ttl = match result {
+            Ok(_) => self.cache.ttl,
+            Err(
+                StorageError::NoSuchKey | StorageError::NotFound(_) | StorageError::AccessDenied(_),
+            ) => self.cache.negative_ttl,
+            Err(_) => Duration::ZERO,
+        };
+        if !ttl.is_zero() {
+            self.insert(bucket, result.clone(), ttl);
+        }
+        result
+    }
+
+    /// Tries each owner in turn, as HeadBucket only accepts one expected owner.
+    /// A bucket of another account is denied, while other errors end the search.
+    async fn head_bucket<T>(&self, storage: &T, bucket: &str) -> Result<String, StorageError>
+    where
+        T: Storage + Send + Sync + ?Sized,
+    {
+        let mut result = Err(StorageError::AccessDenied(
+            "no bucket owner is allowed".to_string(),
+        ));
+        for owner in &self.owners {
+            result = match storage.head_bucket(bucket, owner).await {
+                Ok(()) => return Ok(owner.clone()),
+                Err(e @ StorageError::AccessDenied(_)) => Err(e),
+                Err(e) => return Err(e),
+            };
+        }
+        result
+    }
+
+    fn cached(&self, bucket: &str) -> Option<Result<String, StorageError>> {
+        self.verdicts
+            .get(bucket)
+            .filter(|verdict| verdict.expires_at > Instant::now())
+            .map(|verdict| verdict.result)
+    }
+
+    fn insert(&self, bucket: &str, result: Result<String, StorageError>, ttl: Duration) {
+        self.verdicts.insert(
+            bucket,
+            Verdict {
+                result,
+                expires_at: Instant::now() + ttl,
+            },
+        );
+    }
+}
+
+/// Objects in memory in buckets belonging to `owner`, which tests can change.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct OwnedStorage {
+    pub memory: crate::memory::Memory,
+    pub owner: std::sync::RwLock<String>,
+}
+
+#[cfg(test)]
+impl OwnedStorage {
+    pub fn new(owner: &str) -> Self {
+        Self {
+            memory: Default::default(),
+            owner: std::sync::RwLock::new(owner.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl Storage for OwnedStorage {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<crate::storage::GetObjectResult, StorageError> {
+        self.memory.get_object(bucket, key, version_id).await
+    }
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<crate::storage::HeadObjectResult, StorageError> {
+        self.memory.head_object(bucket, key, version_id).await
+    }
+
+    async fn head_bucket(
+        &self,
+        _bucket: &str,
+        expected_bucket_owner: &str,
+    ) -> Result<(), StorageError> {
+        if *self.owner.read().unwrap() == expected_bucket_owner {
+            Ok(())
+        } else {
+            Err(StorageError::AccessDenied("AccessDenied".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{GetObjectResult, HeadObjectResult};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_case::test_case;
+
+    #[derive(Debug)]
+    struct CountingStorage {
+        head_bucket_result: Result<(), StorageError>,
+        head_bucket_calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for CountingStorage {
+        async fn get_object(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _version_id: Option<&str>,
+        ) -> Result<GetObjectResult, StorageError> {
+            Err(StorageError::NoSuchKey)
+        }
+
+        async fn head_object(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _version_id: Option<&str>,
+        ) -> Result<HeadObjectResult, StorageError> {
+            Err(StorageError::NoSuchKey)
+        }
+
+        async fn head_bucket(
+            &self,
+            _bucket: &str,
+            _expected_bucket_owner: &str,
+        ) -> Result<(), StorageError> {
+            self.head_bucket_calls.fetch_add(1, Ordering::Relaxed);
+            self.head_bucket_result.clone()
+        }
+    }
+
+    #[test_case("222222222222", Some("222222222222"); "second allowed owner")]
+    #[test_case("333333333333", None; "owner not allowed")]
+    #[tokio::test]
+    async fn test_check_allowed_owners(owner: &str, expected: Option<&str>) {
+        let check = BucketOwnerCheck::new(
+            vec!["111111111111".to_string(), "222222222222".to_string()],
+            CACHE,
+        );
+        let result = check
+            .check(&OwnedStorage::new(owner), "foo.example.com")
+            .await;
+        assert_eq!(result.ok().as_deref(), expected);
+    }
+
+    const CACHE: BucketOwnerCacheConfig = BucketOwnerCacheConfig {
+        ttl: Duration::from_secs(60),
+        negative_ttl: Duration::from_secs(60),
+    };
+
+    #[test_case(Ok(()), CACHE, 1; "owned bucket is cached")]
+    #[test_case(Err(StorageError::AccessDenied("AccessDenied".to_string())), CACHE, 1; "denied bucket is cached")]
+    #[test_case(Err(StorageError::Throttled("SlowDown".to_string())), CACHE, 2; "throttling is not cached")]
+    #[test_case(Ok(()), BucketOwnerCacheConfig::default(), 2; "zero ttl disables caching")]
+    #[tokio::test]
+    async fn test_check(
+        head_bucket_result: Result<(), StorageError>,
+        cache: BucketOwnerCacheConfig,
+        expected_calls: usize,
+    ) {
+        let expected_ok = head_bucket_result.is_ok();
+        let storage = CountingStorage {
+            head_bucket_result,
+            head_bucket_calls: AtomicUsize::new(0),
+        };
+        let check = BucketOwnerCheck::new(vec!["012345678901".to_string()], cache);
+
+        for _ in 0..2 {
+            assert_eq!(
+                check.check(&storage, "foo.example.com").await.is_ok(),
+                expected_ok
+            );
+        }
+        assert_eq!(
+            storage.head_bucket_calls.load(Ordering::Relaxed),
+            expected_calls
+        );
+    }
+}
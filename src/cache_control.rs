@@ -0,0 +1,111 @@
+use crate::config::CacheControlRule;
+use crate::response;
+use crate::storage::GetObjectResult;
+use hyper::header::{HeaderMap, HeaderValue, CACHE_CONTROL, EXPIRES};
+
+/// Sets the caching headers of an object response from the first rule matching its key.
+/// The object's `Expires` metadata is dropped whenever a rule's value is sent, so that the two never disagree.
+/// Without a rule's value, it is sent as is, with or without the object's `Cache-Control`.
+pub fn apply(
+    rules: &[CacheControlRule],
+    key: &str,
+    object: &GetObjectResult,
+    headers: &mut HeaderMap,
+) {
+    let rule = rules.iter().find(|rule| rule.pattern.0.is_match(key));
+    let object_cache_control = object
+        .cache_control()
+        .and_then(|value| HeaderValue::from_str(value).ok());
+
+    match rule {
+        Some(rule) if !(rule.prefer_object && object_cache_control.is_some()) => {
+            headers.insert(CACHE_CONTROL, rule.value.0.clone());
+        }
+        _ => {
+            if let Some(value) = object_cache_control {
+                headers.insert(CACHE_CONTROL, value);
+            }
+            if let Some(value) = object
+                .expires()
+                .and_then(|value| HeaderValue::from_str(value).ok())
+            {
+                headers.insert(EXPIRES, value);
+            }
+        }
+    }
+
+    for name in rule.iter().flat_map(|rule| &rule.vary) {
+        response::append_vary(headers, name.0.as_str());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{KeyPattern, ResponseHeaderName, ResponseHeaderValue};
+    use bytes::Bytes;
+    use hyper::header::{ACCEPT_ENCODING, VARY};
+    use regex::Regex;
+    use test_case::test_case;
+
+    const EXPIRES_AT: &str = "Wed, 21 Oct 2026 07:28:00 GMT";
+
+    fn rules() -> Vec<CacheControlRule> {
+        vec![
+            CacheControlRule {
+                pattern: KeyPattern(Regex::new(r"^assets/.*\.js$").unwrap()),
+                value: ResponseHeaderValue(HeaderValue::from_static(
+                    "public, max-age=31536000, immutable",
+                )),
+                prefer_object: false,
+                vary: vec![ResponseHeaderName(ACCEPT_ENCODING)],
+            },
+            CacheControlRule {
+                pattern: KeyPattern(Regex::new(r"\.html$").unwrap()),
+                value: ResponseHeaderValue(HeaderValue::from_static("no-cache")),
+                prefer_object: true,
+                vary: Vec::new(),
+            },
+        ]
+    }
+
+    fn object(cache_control: Option<&str>) -> GetObjectResult {
+        GetObjectResult::builder()
+            .body(Bytes::new())
+            .cache_control(cache_control.map(str::to_string))
+            .expires(Some(EXPIRES_AT.to_string()))
+            .build()
+    }
+
+    #[test_case("assets/app.js", Some("max-age=60"), Some("public, max-age=31536000, immutable"), None; "rule overrides object")]
+    #[test_case("index.html", Some("max-age=60"), Some("max-age=60"), Some(EXPIRES_AT); "object preferred")]
+    #[test_case("index.html", None, Some("no-cache"), None; "rule as fallback")]
+    #[test_case("data.json", Some("max-age=60"), Some("max-age=60"), Some(EXPIRES_AT); "no rule")]
+    #[test_case("data.json", None, None, Some(EXPIRES_AT); "no rule nor object cache control")]
+    fn test_apply(
+        key: &str,
+        object_cache_control: Option<&str>,
+        expected_cache_control: Option<&str>,
+        expected_expires: Option<&str>,
+    ) {
+        let mut headers = HeaderMap::new();
+        apply(&rules(), key, &object(object_cache_control), &mut headers);
+        let get = |name| {
+            headers
+                .get(name)
+                .map(|value: &HeaderValue| value.to_str().unwrap())
+        };
+
+        assert_eq!(get(CACHE_CONTROL), expected_cache_control);
+        assert_eq!(get(EXPIRES), expected_expires);
+    }
+
+    #[test]
+    fn test_apply_vary() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        apply(&rules(), "assets/app.js", &object(None), &mut headers);
+
+        assert_eq!(headers.get_all(VARY).iter().count(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::signature::{self, Policy};
+use std::net::IpAddr;
+
+/// Environment variable holding the secret used by `sign`, kept out of the command line.
+const SIGNING_SECRET_ENV: &str = "GW_SIGNING_SECRET";
+const DEFAULT_EXPIRES_IN_SECS: u64 = 3600;
+const OPTIONS: [&str; 4] = ["--key-id", "--prefix", "--expires-in", "--ip"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum CliError {
+    #[error("unknown subcommand: {0}")]
+    UnknownSubcommand(String),
+    #[error("unknown option: {0}")]
+    UnknownOption(String),
+    #[error("missing value of {0}")]
+    MissingValue(String),
+    #[error("invalid value of {0}: {1}")]
+    InvalidValue(String, String),
+    #[error("{0} is not set")]
+    MissingSecret(&'static str),
+}
+
+pub const USAGE: &str = "usage: storage-gateway sign --key-id <id> --prefix <path> [--expires-in <secs>] [--ip <addr>]
+  The secret of the key is read from GW_SIGNING_SECRET. Prints a token for the gw_token query parameter or cookie.";
+
+/// Runs the subcommand in `args`, which exclude the program name.
+pub fn run(args: Vec<String>) -> Result<(), CliError> {
+    let mut args = args.into_iter();
+    match args.next().as_deref() {
+        Some("sign") => {
+            let secret = std::env::var(SIGNING_SECRET_ENV)
+                .map_err(|_| CliError::MissingSecret(SIGNING_SECRET_ENV))?;
+            println!("{}", sign(args, secret.as_bytes(), signature::now())?);
+            Ok(())
+        }
+        Some(subcommand) => Err(CliError::UnknownSubcommand(subcommand.to_string())),
+        None => Err(CliError::UnknownSubcommand(String::new())),
+    }
+}
+
+fn sign<I>(mut args: I, secret: &[u8], now: u64) -> Result<String, CliError>
+where
+    I: Iterator<Item = String>,
+{
+    let mut key_id = None;
+    let mut path_prefix = None;
+    let mut expires_in = DEFAULT_EXPIRES_IN_SECS;
+    let mut ip = None;
+    while let Some(option) = args.next() {
+        if !OPTIONS.contains(&option.as_str()) {
+            return Err(CliError::UnknownOption(option));
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| CliError::MissingValue(option.clone()))?;
+        match option.as_str() {
+            "--key-id" => key_id = Some(value),
+            "--prefix" => path_prefix = Some(value),
+            "--expires-in" => {
+                expires_in = value
+                    .parse()
+                    .map_err(|_| CliError::InvalidValue(option, value))?
+            }
+            "--ip" => {
+                ip = Some(
+                    value
+                        .parse::<IpAddr>()
+                        .map_err(|_| CliError::InvalidValue(option, value))?,
+                )
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    let key_id = key_id.ok_or_else(|| CliError::MissingValue("--key-id".to_string()))?;
+    let policy = Policy {
+        expires: now + expires_in,
+        path_prefix: path_prefix.ok_or_else(|| CliError::MissingValue("--prefix".to_string()))?,
+        ip,
+    };
+
+    signature::sign(&policy, &key_id, secret)
+        .map_err(|e| CliError::InvalidValue("--prefix".to_string(), e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<String>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_sign() {
+        let token = sign(
+            args(&[
+                "--key-id",
+                "2024-02",
+                "--prefix",
+                "/private/",
+                "--ip",
+                "192.0.2.1",
+            ]),
+            b"secret",
+            signature::now(),
+        )
+        .unwrap();
+        let policy = signature::verify(
+            &token,
+            [("2024-02", b"secret".as_slice())],
+            "/private/a.pdf",
+            "192.0.2.1".parse().ok(),
+        )
+        .unwrap();
+        assert_eq!(policy.path_prefix, "/private/");
+    }
+
+    #[test]
+    fn test_sign_invalid() {
+        assert!(matches!(
+            sign(args(&["--prefix", "/"]), b"secret", 0),
+            Err(CliError::MissingValue(_))
+        ));
+        assert!(matches!(
+            sign(
+                args(&["--key-id", "a", "--prefix", "/", "--expires-in", "soon"]),
+                b"secret",
+                0
+            ),
+            Err(CliError::InvalidValue(_, _))
+        ));
+        assert!(matches!(
+            sign(args(&["--key-id", "a", "--prefix", "/a;b"]), b"secret", 0),
+            Err(CliError::InvalidValue(_, _))
+        ));
+        assert!(matches!(
+            sign(args(&["--key-id", "a", "--scope", "/"]), b"secret", 0),
+            Err(CliError::UnknownOption(_))
+        ));
+    }
+}
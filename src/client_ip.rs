@@ -0,0 +1,155 @@
+use crate::config::{Cidr, ForwardedHeader};
+use hyper::header::{HeaderMap, FORWARDED};
+use std::net::{IpAddr, SocketAddr};
+
+/// Address of the client a request is made for, attached to each request by the gateway service.
+/// Behind trusted proxies it is the address they forwarded the request for.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Walks the addresses forwarded by proxies from the nearest one back while the hop that added them is trusted:
+/// the PROXY protocol source address, then the addresses of `forwarded_header`.
+pub fn resolve(
+    peer: IpAddr,
+    proxied: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[Cidr],
+    forwarded_header: ForwardedHeader,
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(ip));
+    let hops = proxied
+        .map(Some)
+        .into_iter()
+        .chain(forwarded_for(headers, forwarded_header).into_iter().rev());
+
+    let mut client = peer.to_canonical();
+    for hop in hops {
+        if !is_trusted(&client) {
+            break;
+        }
+        // An obfuscated or unknown address ends the chain at the proxy that wrote it.
+        let Some(ip) = hop else {
+            break;
+        };
+        client = ip.to_canonical();
+    }
+
+    client
+}
+
+fn forwarded_for(headers: &HeaderMap, forwarded_header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<&str>>()
+    };
+
+    match forwarded_header {
+        ForwardedHeader::XForwardedFor => values("X-Forwarded-For")
+            .into_iter()
+            .map(parse_node)
+            .collect(),
+        ForwardedHeader::Forwarded => values(FORWARDED.as_str())
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect(),
+    }
+}
+
+/// Parses `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `"[2001:db8::1]:4711"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.split_once(']'))
+                .and_then(|(ip, _)| ip.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("203.0.113.7", &[], "203.0.113.7"; "direct")]
+    #[test_case("203.0.113.7", &[("X-Forwarded-For", "192.0.2.1")], "203.0.113.7"; "untrusted peer")]
+    #[test_case("10.0.0.2", &[("X-Forwarded-For", "192.0.2.1")], "192.0.2.1"; "trusted peer")]
+    #[test_case("10.0.0.2", &[("X-Forwarded-For", "198.51.100.9, 192.0.2.1, 10.0.0.1")], "192.0.2.1"; "spoofed first hop")]
+    #[test_case("10.0.0.2", &[("X-Forwarded-For", "10.0.0.3, 10.0.0.1")], "10.0.0.3"; "only trusted hops")]
+    #[test_case("10.0.0.2", &[("X-Forwarded-For", "192.0.2.1:4711")], "192.0.2.1"; "port")]
+    #[test_case("10.0.0.2", &[("Forwarded", "for=10.1.1.1"), ("X-Forwarded-For", "192.0.2.1")], "192.0.2.1"; "client forwarded ignored")]
+    #[test_case("::ffff:10.0.0.2", &[("X-Forwarded-For", "192.0.2.1")], "192.0.2.1"; "ipv4 mapped peer")]
+    fn test_resolve(peer: &str, headers: &[(&'static str, &str)], expected: &str) {
+        assert_resolve(
+            peer,
+            None,
+            headers,
+            ForwardedHeader::XForwardedFor,
+            expected,
+        );
+    }
+
+    #[test_case("10.0.0.2", &[("Forwarded", r#"for=192.0.2.1;proto=https, for="[2001:db8::1]:4711""#)], "2001:db8::1"; "forwarded ipv6")]
+    #[test_case("10.0.0.2", &[("Forwarded", "for=192.0.2.1"), ("X-Forwarded-For", "10.1.1.1")], "192.0.2.1"; "client x forwarded for ignored")]
+    #[test_case("10.0.0.2", &[("X-Forwarded-For", "192.0.2.1")], "10.0.0.2"; "no forwarded")]
+    #[test_case("10.0.0.2", &[("Forwarded", "for=unknown")], "10.0.0.2"; "unknown node")]
+    fn test_resolve_forwarded(peer: &str, headers: &[(&'static str, &str)], expected: &str) {
+        assert_resolve(peer, None, headers, ForwardedHeader::Forwarded, expected);
+    }
+
+    #[test_case("10.0.0.2", "192.0.2.1", &[], "192.0.2.1"; "trusted load balancer")]
+    #[test_case("203.0.113.7", "192.0.2.1", &[], "203.0.113.7"; "untrusted load balancer")]
+    #[test_case("10.0.0.2", "10.0.0.3", &[("X-Forwarded-For", "192.0.2.1")], "192.0.2.1"; "proxy behind load balancer")]
+    #[test_case("10.0.0.2", "192.0.2.1", &[("X-Forwarded-For", "198.51.100.9")], "192.0.2.1"; "untrusted source forwarded for")]
+    fn test_resolve_proxied(
+        peer: &str,
+        proxied: &str,
+        headers: &[(&'static str, &str)],
+        expected: &str,
+    ) {
+        assert_resolve(
+            peer,
+            Some(proxied),
+            headers,
+            ForwardedHeader::XForwardedFor,
+            expected,
+        );
+    }
+
+    fn assert_resolve(
+        peer: &str,
+        proxied: Option<&str>,
+        headers: &[(&'static str, &str)],
+        forwarded_header: ForwardedHeader,
+        expected: &str,
+    ) {
+        let trusted_proxies = [Cidr::try_from("10.0.0.0/8".to_string()).unwrap()];
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.append(*name, value.parse().unwrap());
+        }
+
+        assert_eq!(
+            resolve(
+                peer.parse().unwrap(),
+                proxied.map(|ip| ip.parse().unwrap()),
+                &header_map,
+                &trusted_proxies,
+                forwarded_header
+            ),
+            expected.parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config::CorsRule;
+use crate::metrics;
+use crate::storage::{GetObjectResult, HeadObjectResult, SharedStorage, Storage, StorageError};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConcurrencyError {
+    #[error("no slot free within {0:?}")]
+    Overloaded(Duration),
+}
+
+/// Caps the requests in flight per host. A host's slots are dropped once none of its requests is in flight
+/// or waiting, so wildcard domains do not grow the map without bound.
+#[derive(Debug)]
+pub struct HostLimiter {
+    limit: usize,
+    wait: Duration,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    pub fn new(limit: usize, wait: Duration) -> Self {
+        Self {
+            limit: limit.min(Semaphore::MAX_PERMITS),
+            wait,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits up to the load shed wait for a slot of the host, held until the returned permit is dropped.
+    pub async fn acquire(self: &Arc<Self>, host: &str) -> Result<HostPermit, ConcurrencyError> {
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        let mut permit = HostPermit {
+            limiter: self.clone(),
+            host: host.to_string(),
+            semaphore,
+            permit: None,
+        };
+
+        match tokio::time::timeout(self.wait, permit.semaphore.clone().acquire_owned()).await {
+            Ok(Ok(slot)) => {
+                permit.permit = Some(slot);
+                Ok(permit)
+            }
+            _ => {
+                metrics::SHED_REQUESTS.inc();
+                Err(ConcurrencyError::Overloaded(self.wait))
+            }
+        }
+    }
+}
+
+/// A request's slot of its host, also kept while waiting so that the host is not dropped meanwhile.
+#[derive(Debug)]
+pub struct HostPermit {
+    limiter: Arc<HostLimiter>,
+    host: String,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let mut hosts = self.limiter.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        self.permit.take();
+        // Only the map and this permit refer to the slots when no other request of the host is in flight or waiting.
+        if Arc::strong_count(&self.semaphore) == 2 {
+            hosts.remove(&self.host);
+        }
+    }
+}
+
+/// A storage whose calls share a fixed number of slots with every storage built from the same semaphore.
+/// Calls that wait longer than `wait` for a slot fail as throttled, which is answered with 503.
+#[derive(Debug)]
+pub struct Limited {
+    storage: SharedStorage,
+    slots: Arc<Semaphore>,
+    wait: Duration,
+}
+
+impl Limited {
+    pub fn new(storage: SharedStorage, slots: Arc<Semaphore>, wait: Duration) -> Self {
+        Self {
+            storage,
+            slots,
+            wait,
+        }
+    }
+
+    async fn slot(&self) -> Result<SemaphorePermit<'_>, StorageError> {
+        match tokio::time::timeout(self.wait, self.slots.acquire()).await {
+            Ok(Ok(slot)) => Ok(slot),
+            _ => {
+                metrics::SHED_REQUESTS.inc();
+                Err(StorageError::Throttled(format!(
+                    "no storage slot free within {:?}",
+                    self.wait
+                )))
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for Limited {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        let _slot = self.slot().await?;
+        self.storage.get_object(bucket, key, version_id).await
+    }
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        let _slot = self.slot().await?;
+        self.storage.head_object(bucket, key, version_id).await
+    }
+
+    async fn get_object_owned_by(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expected_bucket_owner: &str,
+    ) -> Result<GetObjectResult, StorageError> {
+        let _slot = self.slot().await?;
+        self.storage
+            .get_object_owned_by(bucket, key, version_id, expected_bucket_owner)
+            .await
+    }
+
+    async fn head_object_owned_by(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expected_bucket_owner: &str,
+    ) -> Result<HeadObjectResult, StorageError> {
+        let _slot = self.slot().await?;
+        self.storage
+            .head_object_owned_by(bucket, key, version_id, expected_bucket_owner)
+            .await
+    }
+
+    async fn head_bucket(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: &str,
+    ) -> Result<(), StorageError> {
+        let _slot = self.slot().await?;
+        self.storage
+            .head_bucket(bucket, expected_bucket_owner)
+            .await
+    }
+
+    async fn get_bucket_cors(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: Option<&str>,
+    ) -> Result<Vec<CorsRule>, StorageError> {
+        let _slot = self.slot().await?;
+        self.storage
+            .get_bucket_cors(bucket, expected_bucket_owner)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    const WAIT: Duration = Duration::from_millis(10);
+
+    #[tokio::test]
+    async fn test_host_limiter() {
+        let limiter = Arc::new(HostLimiter::new(2, WAIT));
+
+        let first = limiter.acquire("foo.example.com").await.unwrap();
+        let second = limiter.acquire("foo.example.com").await.unwrap();
+        assert!(matches!(
+            limiter.acquire("foo.example.com").await,
+            Err(ConcurrencyError::Overloaded(_))
+        ));
+        let other_host = limiter.acquire("bar.example.com").await.unwrap();
+
+        drop(first);
+        let third = limiter.acquire("foo.example.com").await.unwrap();
+        assert_eq!(limiter.hosts.lock().unwrap().len(), 2);
+
+        drop((second, third, other_host));
+        assert!(limiter.hosts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_limited() {
+        let memory = Memory::default();
+        memory.insert("foo.example.com", "index.html", "<h1>index</h1>", None);
+        let slots = Arc::new(Semaphore::new(1));
+        let storage = Limited::new(Arc::new(memory), slots.clone(), WAIT);
+
+        assert!(storage
+            .get_object("foo.example.com", "index.html", None)
+            .await
+            .is_ok());
+
+        let _busy = slots.acquire().await.unwrap();
+        assert!(matches!(
+            storage
+                .get_object("foo.example.com", "index.html", None)
+                .await,
+            Err(StorageError::Throttled(_))
+        ));
+    }
+}
@@ -1,5 +1,14 @@
-use config::{Config, Environment};
+use config::{Config, Environment, File};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+use ipnet::IpNet;
+use regex::Regex;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
@@ -9,10 +18,854 @@ pub struct AppConfig {
     pub no_such_key_redirect_object: Option<String>,
     #[serde(default)]
     pub allow_cross_account: bool,
+    #[serde(default)]
+    pub allowed_bucket_owners: Vec<String>,
+    #[serde(default)]
+    pub allowed_ips: Vec<Cidr>,
+    #[serde(default)]
+    pub denied_ips: Vec<Cidr>,
+    /// Proxies whose `forwarded_header` is believed.
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+    /// The header trusted proxies add the client address to. The other one is ignored, as a client could send it.
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+    /// Expect a PROXY protocol header at the start of each gateway connection.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default)]
+    pub management_proxy_protocol: bool,
     #[serde(default = "default_gateway_port")]
     pub gateway_port: u16,
     #[serde(default = "default_management_port")]
     pub management_port: u16,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub sites: Vec<SiteConfig>,
+    pub aws_connect_timeout_ms: Option<u64>,
+    pub aws_read_timeout_ms: Option<u64>,
+    pub aws_operation_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub aws_retry_mode: RetryMode,
+    pub aws_max_attempts: Option<u32>,
+    pub aws_pool_max_idle_per_host: Option<usize>,
+    pub aws_pool_idle_timeout_ms: Option<u64>,
+    pub request_timeout_ms: Option<u64>,
+    /// Open gateway connections beyond which new ones wait in the listen backlog.
+    pub max_connections: Option<usize>,
+    pub max_in_flight_per_host: Option<usize>,
+    pub s3_max_concurrency: Option<usize>,
+    /// How long a request may wait for a per-host or S3 slot before it is shed with 503.
+    #[serde(default = "default_load_shed_wait_ms")]
+    pub load_shed_wait_ms: u64,
+    #[serde(default = "default_header_read_timeout_ms")]
+    pub header_read_timeout_ms: u64,
+    #[serde(default = "default_keep_alive_timeout_ms")]
+    pub keep_alive_timeout_ms: u64,
+    #[serde(default = "default_response_write_timeout_ms")]
+    pub response_write_timeout_ms: u64,
+    #[serde(default = "default_max_header_bytes")]
+    pub max_header_bytes: usize,
+    #[serde(default = "default_max_headers")]
+    pub max_headers: usize,
+    #[serde(default = "default_max_uri_length")]
+    pub max_uri_length: usize,
+    pub s3_endpoint_url: Option<String>,
+    pub s3_force_path_style: Option<bool>,
+    pub s3_region: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<Secret>,
+    pub s3_session_token: Option<Secret>,
+    pub s3_profile: Option<String>,
+    pub s3_role_arn: Option<String>,
+    pub s3_external_id: Option<String>,
+    pub s3_role_session_name: Option<String>,
+    pub sts_endpoint_url: Option<String>,
+    #[serde(default = "default_bucket_owner_cache_ttl_ms")]
+    pub bucket_owner_cache_ttl_ms: u64,
+    #[serde(default = "default_bucket_owner_negative_cache_ttl_ms")]
+    pub bucket_owner_negative_cache_ttl_ms: u64,
+}
+
+/// A config value that is not shown in logs.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+/// An address range such as `10.0.0.0/8`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr(IpNet);
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(&ip.to_canonical())
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .parse::<IpNet>()
+            .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+            .map(Cidr)
+            .map_err(|_| format!("invalid address range: {}", value))
+    }
+}
+
+/// Response headers by name, e.g. `{ "Cache-Control" = "no-store" }`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct ResponseHeaders(pub HeaderMap);
+
+impl TryFrom<HashMap<String, String>> for ResponseHeaders {
+    type Error = String;
+
+    fn try_from(value: HashMap<String, String>) -> Result<Self, Self::Error> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in value {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name: {}", name))?;
+            let value = HeaderValue::from_str(&value)
+                .map_err(|_| format!("invalid value of header: {}", name))?;
+            headers.insert(header, value);
+        }
+        Ok(Self(headers))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ResponseHeaderName(pub HeaderName);
+
+impl TryFrom<String> for ResponseHeaderName {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        HeaderName::from_bytes(value.as_bytes())
+            .map(ResponseHeaderName)
+            .map_err(|_| format!("invalid header name: {}", value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ResponseHeaderValue(pub HeaderValue);
+
+impl TryFrom<String> for ResponseHeaderValue {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        HeaderValue::from_str(&value)
+            .map(ResponseHeaderValue)
+            .map_err(|_| format!("invalid header value: {}", value))
+    }
+}
+
+/// Object keys matched by a glob, e.g. `{ glob = "assets/**/*.js" }`, or by a regular expression,
+/// e.g. `{ regex = '^assets/.+\.[0-9a-f]{8}\.js$' }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "KeyPatternConfig")]
+pub struct KeyPattern(pub Regex);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum KeyPatternConfig {
+    Glob(String),
+    Regex(String),
+}
+
+impl TryFrom<KeyPatternConfig> for KeyPattern {
+    type Error = String;
+
+    fn try_from(value: KeyPatternConfig) -> Result<Self, Self::Error> {
+        let (pattern, regex) = match value {
+            KeyPatternConfig::Glob(glob) => (glob_regex(&glob), glob),
+            KeyPatternConfig::Regex(regex) => (regex.clone(), regex),
+        };
+        Regex::new(&pattern)
+            .map(KeyPattern)
+            .map_err(|e| format!("invalid key pattern: {}: {}", regex, e))
+    }
+}
+
+/// The status of an SPA fallback response, a success or a client error such as `200` or `404`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u16")]
+pub struct FallbackStatus(pub StatusCode);
+
+impl TryFrom<u16> for FallbackStatus {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        status_code(value, |status| {
+            status.is_success() || status.is_client_error()
+        })
+        .map(FallbackStatus)
+    }
+}
+
+/// The status of a rewrite redirect: `301`, `302`, `303`, `307` or `308`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u16")]
+pub struct RedirectStatus(pub StatusCode);
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        status_code(value, |status| {
+            matches!(
+                status,
+                StatusCode::MOVED_PERMANENTLY
+                    | StatusCode::FOUND
+                    | StatusCode::SEE_OTHER
+                    | StatusCode::TEMPORARY_REDIRECT
+                    | StatusCode::PERMANENT_REDIRECT
+            )
+        })
+        .map(RedirectStatus)
+    }
+}
+
+/// The status of a fixed rewrite response, a success or an error such as `200` or `503`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "u16")]
+pub struct RespondStatus(pub StatusCode);
+
+impl TryFrom<u16> for RespondStatus {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        status_code(value, |status| {
+            status.is_success() || status.is_client_error() || status.is_server_error()
+        })
+        .map(RespondStatus)
+    }
+}
+
+/// The status code `value` when `allowed` accepts it.
+fn status_code(value: u16, allowed: fn(StatusCode) -> bool) -> Result<StatusCode, String> {
+    StatusCode::from_u16(value)
+        .ok()
+        .filter(|status| allowed(*status))
+        .ok_or_else(|| format!("invalid status: {}", value))
+}
+
+/// A regular expression matched against a request value such as the path or a header.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern(pub Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Regex::new(&value)
+            .map(Pattern)
+            .map_err(|e| format!("invalid pattern: {}: {}", value, e))
+    }
+}
+
+/// Translates a glob to an anchored regular expression. `*` and `?` stay within a path segment,
+/// `**` crosses segments, `[...]` is a character class and `{a,b}` an alternation.
+fn glob_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    let mut in_alternation = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            '{' if !in_alternation => {
+                in_alternation = true;
+                regex.push_str("(?:");
+            }
+            ',' if in_alternation => regex.push('|'),
+            '}' if in_alternation => {
+                in_alternation = false;
+                regex.push(')');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryMode {
+    #[default]
+    Standard,
+    Adaptive,
+}
+
+/// How long bucket owner check results are reused. A zero TTL disables caching.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BucketOwnerCacheConfig {
+    pub ttl: Duration,
+    /// TTL of a denied check, i.e. a bucket that is missing or owned by another account.
+    pub negative_ttl: Duration,
+}
+
+/// Limits of the gateway's concurrent work. `None` leaves a limit off.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConcurrencyConfig {
+    pub max_connections: Option<usize>,
+    pub max_in_flight_per_host: Option<usize>,
+    pub s3_max_concurrency: Option<usize>,
+    pub load_shed_wait: Duration,
+}
+
+/// Timeouts and request limits of the gateway and management connections. `None` leaves one to hyper's default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionConfig {
+    pub header_read_timeout: Option<Duration>,
+    /// How long a connection without a request in flight is kept open.
+    pub keep_alive_timeout: Option<Duration>,
+    pub response_write_timeout: Option<Duration>,
+    pub max_header_bytes: Option<usize>,
+    pub max_headers: Option<usize>,
+    pub max_uri_length: Option<usize>,
+}
+
+/// Settings shared by the S3 and STS clients.
+#[derive(Debug, Clone, Default)]
+pub struct AwsClientConfig {
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub operation_timeout: Option<Duration>,
+    pub retry_mode: RetryMode,
+    pub max_attempts: Option<u32>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub sts_endpoint_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SiteConfig {
+    #[serde(default)]
+    pub domains: Vec<String>,
+    pub root_object: Option<String>,
+    pub subdir_root_object: Option<String>,
+    pub no_such_key_redirect_object: Option<String>,
+    #[serde(default)]
+    pub error_documents: HashMap<u16, String>,
+    pub spa_fallback: Option<SpaFallbackConfig>,
+    pub storage: Option<StorageConfig>,
+    /// Accounts the site's buckets may belong to. Empty uses the top level list.
+    #[serde(default)]
+    pub allowed_bucket_owners: Vec<String>,
+    /// Client addresses the site is served to. Empty allows any address not in `denied_ips`.
+    #[serde(default)]
+    pub allowed_ips: Vec<Cidr>,
+    #[serde(default)]
+    pub denied_ips: Vec<Cidr>,
+    pub release_manifest: Option<ReleaseManifestConfig>,
+    pub signed_urls: Option<SignedUrlConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+    pub cors: Option<CorsConfig>,
+    /// `Cache-Control` rules for objects by key. The first matching rule applies.
+    #[serde(default)]
+    pub cache_control: Vec<CacheControlRule>,
+    /// Rewrite, redirect and header rules, evaluated in order before the key is resolved.
+    #[serde(default)]
+    pub rewrites: Vec<RewriteRule>,
+    pub i18n: Option<I18nConfig>,
+    /// Serve `about.html` for `/about` and redirect `/about.html` to `/about`.
+    #[serde(default)]
+    pub clean_urls: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    S3(S3StorageConfig),
+    Filesystem {
+        root: PathBuf,
+    },
+    Memory {
+        #[serde(default)]
+        objects: Vec<MemoryObjectConfig>,
+    },
+}
+
+impl SiteConfig {
+    /// The accounts the site's buckets may belong to. `allowed_bucket_owners` always applies.
+    /// Without it, sites assuming a role expect the role's account and other AWS S3 sites expect
+    /// `self_account_id`, unless cross account access is allowed. Other backends have no owner to check.
+    pub fn expected_bucket_owners(
+        &self,
+        self_account_id: Option<&str>,
+        allow_cross_account: bool,
+    ) -> Vec<String> {
+        match self.storage.clone().unwrap_or_default() {
+            StorageConfig::S3(s3) if s3.endpoint_url.is_none() => {
+                if !self.allowed_bucket_owners.is_empty() {
+                    return self.allowed_bucket_owners.clone();
+                }
+                if allow_cross_account {
+                    return Vec::new();
+                }
+                s3.role_account_id()
+                    .or(self_account_id)
+                    .map(str::to_string)
+                    .into_iter()
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether the bucket owner check needs the gateway's own account id.
+    pub fn needs_self_account_id(&self) -> bool {
+        self.allowed_bucket_owners.is_empty()
+            && matches!(
+                self.storage.clone().unwrap_or_default(),
+                StorageConfig::S3(s3) if s3.endpoint_url.is_none() && s3.role_arn.is_none()
+            )
+    }
+
+    /// Whether the site is served to `client_ip`. Denied ranges win over allowed ones,
+    /// and an unknown address is only served when there are no rules.
+    pub fn allows_ip(&self, client_ip: Option<IpAddr>) -> bool {
+        let Some(ip) = client_ip else {
+            return self.allowed_ips.is_empty() && self.denied_ips.is_empty();
+        };
+
+        !self.denied_ips.iter().any(|cidr| cidr.contains(&ip))
+            && (self.allowed_ips.is_empty()
+                || self.allowed_ips.iter().any(|cidr| cidr.contains(&ip)))
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::S3(S3StorageConfig::default())
+    }
+}
+
+/// Connection settings of an S3 client. Unset values use the AWS SDK defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct S3StorageConfig {
+    pub endpoint_url: Option<String>,
+    pub force_path_style: Option<bool>,
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<Secret>,
+    pub session_token: Option<Secret>,
+    pub profile: Option<String>,
+    /// Role assumed through STS to read the buckets, typically in another account.
+    pub role_arn: Option<String>,
+    pub external_id: Option<String>,
+    pub role_session_name: Option<String>,
+}
+
+impl S3StorageConfig {
+    /// Fills unset values from `defaults`.
+    pub fn or(self, defaults: &S3StorageConfig) -> Self {
+        Self {
+            endpoint_url: self.endpoint_url.or(defaults.endpoint_url.clone()),
+            force_path_style: self.force_path_style.or(defaults.force_path_style),
+            region: self.region.or(defaults.region.clone()),
+            access_key_id: self.access_key_id.or(defaults.access_key_id.clone()),
+            secret_access_key: self
+                .secret_access_key
+                .or(defaults.secret_access_key.clone()),
+            session_token: self.session_token.or(defaults.session_token.clone()),
+            profile: self.profile.or(defaults.profile.clone()),
+            role_arn: self.role_arn.or(defaults.role_arn.clone()),
+            external_id: self.external_id.or(defaults.external_id.clone()),
+            role_session_name: self
+                .role_session_name
+                .or(defaults.role_session_name.clone()),
+        }
+    }
+
+    /// Static credentials need both the access key id and the secret.
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.access_key_id, &self.secret_access_key) {
+            (Some(_), None) | (None, Some(_)) => {
+                Err("access_key_id and secret_access_key must be set together".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The account id in `role_arn`, e.g. `123456789012` in `arn:aws:iam::123456789012:role/example`.
+    pub fn role_account_id(&self) -> Option<&str> {
+        self.role_arn
+            .as_deref()?
+            .split(':')
+            .nth(4)
+            .filter(|account_id| !account_id.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemoryObjectConfig {
+    pub bucket: String,
+    pub key: String,
+    pub body: String,
+    pub website_redirect_location: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpaFallbackConfig {
+    pub object: String,
+    /// A success or client error status, `200` by default.
+    #[serde(default = "default_spa_fallback_status")]
+    pub status: FallbackStatus,
+}
+
+fn default_spa_fallback_status() -> FallbackStatus {
+    FallbackStatus(StatusCode::OK)
+}
+
+/// Requires requests to carry a token signed with one of `keys`. Several keys allow rotation.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedUrlConfig {
+    pub keys: Vec<SigningKeyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SigningKeyConfig {
+    pub id: String,
+    pub secret: Secret,
+}
+
+/// Requires a JWT in the `Authorization: Bearer` header or in `cookie`, signed with a key of the JWKS.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwtConfig {
+    pub issuer: String,
+    /// Accepted `aud` values. Empty skips the audience check.
+    #[serde(default)]
+    pub audience: Vec<String>,
+    /// Path of a JWKS file, or an `http(s)` URL it is fetched from.
+    pub jwks: String,
+    #[serde(default = "default_jwks_refresh_interval_ms")]
+    pub jwks_refresh_interval_ms: u64,
+    pub cookie: Option<String>,
+    /// Claims a token must have, with a value equal to or, for arrays, containing the given one.
+    #[serde(default)]
+    pub required_claims: HashMap<String, String>,
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+}
+
+fn default_jwks_refresh_interval_ms() -> u64 {
+    300_000
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+/// Requires HTTP Basic credentials listed in an htpasswd file with bcrypt or argon2 hashes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BasicAuthConfig {
+    pub htpasswd: PathBuf,
+    #[serde(default = "default_basic_auth_realm")]
+    pub realm: String,
+    /// How often the file is checked for changes.
+    #[serde(default = "default_htpasswd_reload_interval_ms")]
+    pub reload_interval_ms: u64,
+    /// Password hashes computed at once. Requests beyond it wait for a slot.
+    #[serde(default = "default_max_concurrent_verifications")]
+    pub max_concurrent_verifications: usize,
+}
+
+fn default_basic_auth_realm() -> String {
+    "storage-gateway".to_string()
+}
+
+fn default_htpasswd_reload_interval_ms() -> u64 {
+    5_000
+}
+
+fn default_max_concurrent_verifications() -> usize {
+    4
+}
+
+/// A token bucket per key: `burst` requests at once, refilled at `requests_per_second`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub key: RateLimitKey,
+    /// Header whose value is the key when `key` is `header`.
+    pub header: Option<String>,
+    /// Header values keyed on their own, e.g. issued API keys. Requests with other values are keyed by client IP.
+    /// Required with the `header` key, as a client could rotate any other value to get a fresh bucket.
+    #[serde(default)]
+    pub header_values: Vec<Secret>,
+    pub requests_per_second: f64,
+    pub burst: u32,
+    /// Upper bound of tracked keys. The least recently seen ones are dropped beyond it.
+    #[serde(default = "default_rate_limit_max_keys")]
+    pub max_keys: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    Host,
+    Header,
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.requests_per_second.is_finite() && self.requests_per_second > 0.0) {
+            return Err("rate_limit.requests_per_second must be a positive number".to_string());
+        }
+        if self.burst < 1 {
+            return Err("rate_limit.burst must be at least 1".to_string());
+        }
+        if self.key == RateLimitKey::Header
+            && (self.header.is_none() || self.header_values.is_empty())
+        {
+            return Err("rate_limit.key header needs header and header_values".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn default_rate_limit_max_keys() -> usize {
+    10_000
+}
+
+/// Cross-origin access to a site, from `rules` or from the CORS configuration of each bucket.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsConfig {
+    #[serde(default)]
+    pub rules: Vec<CorsRule>,
+    /// Use the S3 CORS configuration of the requested bucket instead of `rules`.
+    #[serde(default)]
+    pub from_bucket: bool,
+    /// How long the rules of a bucket are reused before they are read again.
+    #[serde(default = "default_cors_refresh_interval_ms")]
+    pub refresh_interval_ms: u64,
+}
+
+/// Which origins may read a site and how, as in an S3 CORS rule. The first rule matching a request applies.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct CorsRule {
+    /// Origins such as `https://app.example.com`, with at most one `*` wildcard, e.g. `https://*.example.com` or `*`.
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed in preflights, with at most one `*` wildcard each.
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read.
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    pub max_age_secs: Option<u64>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+impl CorsConfig {
+    /// Browsers refuse credentials with `Access-Control-Allow-Origin: *`, and echoing any origin instead would let
+    /// every site read responses with the user's cookies.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.rules.iter().any(|rule| {
+            rule.allow_credentials && rule.allowed_origins.iter().any(|origin| origin == "*")
+        }) {
+            true => Err("cors rules allowing any origin cannot allow credentials".to_string()),
+            false => Ok(()),
+        }
+    }
+}
+
+fn default_cors_refresh_interval_ms() -> u64 {
+    300_000
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    vec!["GET".to_string(), "HEAD".to_string()]
+}
+
+/// Headers added to every response of a site, success or error, applied in the order of the fields.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityHeadersConfig {
+    /// Add the secure defaults to responses that do not carry them.
+    #[serde(default = "default_security_headers_defaults")]
+    pub defaults: bool,
+    /// Added to responses that do not carry the header.
+    #[serde(default)]
+    pub add: ResponseHeaders,
+    /// Replace the header of the response.
+    #[serde(default)]
+    pub set: ResponseHeaders,
+    #[serde(default)]
+    pub remove: Vec<ResponseHeaderName>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            defaults: default_security_headers_defaults(),
+            add: ResponseHeaders::default(),
+            set: ResponseHeaders::default(),
+            remove: Vec::new(),
+        }
+    }
+}
+
+fn default_security_headers_defaults() -> bool {
+    true
+}
+
+/// The `Cache-Control` of the objects whose key matches `pattern`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheControlRule {
+    pub pattern: KeyPattern,
+    pub value: ResponseHeaderValue,
+    /// Keep the object's own `Cache-Control` metadata when it has some, using `value` only as a fallback.
+    #[serde(default)]
+    pub prefer_object: bool,
+    /// Request headers the response depends on, added to `Vary`.
+    #[serde(default)]
+    pub vary: Vec<ResponseHeaderName>,
+}
+
+/// A rule that applies `action` to the requests matching all of its conditions.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RewriteRule {
+    /// Path pattern whose captures, e.g. `$1` or `${slug}`, can be used in the action.
+    pub path: Option<Pattern>,
+    pub host: Option<Pattern>,
+    /// Methods matched, any when empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Patterns of request header values. A missing header does not match.
+    #[serde(default)]
+    pub headers: HashMap<String, Pattern>,
+    /// Patterns of raw query parameter values. A missing parameter does not match.
+    #[serde(default)]
+    pub query: HashMap<String, Pattern>,
+    pub action: RewriteAction,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RewriteAction {
+    /// Serves `path` instead of the requested path. Later rules see the rewritten path.
+    Rewrite { path: String },
+    /// Redirects to `location`, keeping the request's query unless `location` has one.
+    Redirect {
+        location: String,
+        #[serde(default = "default_redirect_status")]
+        status: RedirectStatus,
+    },
+    /// Adds `headers` the response does not carry, and `Vary` names to its own. Later rules are still evaluated.
+    Headers {
+        headers: HashMap<ResponseHeaderName, String>,
+    },
+    /// Responds with a fixed body instead of an object.
+    Respond {
+        status: RespondStatus,
+        #[serde(default)]
+        body: String,
+        content_type: Option<String>,
+    },
+}
+
+fn default_redirect_status() -> RedirectStatus {
+    RedirectStatus(StatusCode::MOVED_PERMANENTLY)
+}
+
+/// Localized objects stored under a key prefix per locale, e.g. `en/` and `ja/`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct I18nConfig {
+    pub locales: Vec<String>,
+    /// Locale served when neither the request nor `Accept-Language` names one of `locales`.
+    pub default_locale: String,
+    #[serde(default)]
+    pub mode: I18nMode,
+    /// Cookie naming the locale, which takes precedence over `Accept-Language`.
+    pub cookie: Option<String>,
+    /// Query parameter naming the locale, which takes precedence over the cookie.
+    pub query: Option<String>,
+}
+
+/// How a request without a locale prefix is served.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum I18nMode {
+    /// Serves the object of the chosen locale at the requested path.
+    #[default]
+    Rewrite,
+    /// Redirects to the path prefixed with the chosen locale.
+    Redirect,
+}
+
+/// A JSON object in each bucket that maps requested keys to the version or key served for them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseManifestConfig {
+    pub object: String,
+    #[serde(default = "default_release_manifest_refresh_interval_ms")]
+    pub refresh_interval_ms: u64,
+}
+
+fn default_release_manifest_refresh_interval_ms() -> u64 {
+    30_000
 }
 
 fn default_gateway_port() -> u16 {
@@ -23,19 +876,429 @@ fn default_management_port() -> u16 {
     8080
 }
 
+fn default_load_shed_wait_ms() -> u64 {
+    100
+}
+
+fn default_header_read_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_keep_alive_timeout_ms() -> u64 {
+    60_000
+}
+
+fn default_response_write_timeout_ms() -> u64 {
+    60_000
+}
+
+fn default_max_header_bytes() -> usize {
+    65_536
+}
+
+fn default_max_headers() -> usize {
+    100
+}
+
+fn default_max_uri_length() -> usize {
+    8_192
+}
+
+fn default_bucket_owner_cache_ttl_ms() -> u64 {
+    300_000
+}
+
+fn default_bucket_owner_negative_cache_ttl_ms() -> u64 {
+    10_000
+}
+
 impl AppConfig {
     pub fn new() -> Self {
-        Config::builder()
+        let mut builder = Config::builder();
+        if let Ok(path) = std::env::var("GW_CONFIG_FILE") {
+            builder = builder.add_source(File::with_name(&path));
+        }
+
+        let config: Self = builder
             .add_source(
                 Environment::with_prefix("GW")
                     .prefix_separator("_")
                     .list_separator(",")
                     .with_list_parse_key("allow_domains")
+                    .with_list_parse_key("allowed_bucket_owners")
+                    .with_list_parse_key("allowed_ips")
+                    .with_list_parse_key("denied_ips")
+                    .with_list_parse_key("trusted_proxies")
                     .try_parsing(true),
             )
             .build()
             .expect("Failed to build config")
             .try_deserialize()
-            .expect("Failed to deserialize config")
+            .expect("Failed to deserialize config");
+        config.validate().expect("Invalid config");
+        config
+    }
+
+    /// Checks settings that are only invalid in combination, once site settings are merged with the top level ones.
+    pub fn validate(&self) -> Result<(), String> {
+        for site in self.sites().iter().chain([&self.default_site()]) {
+            if let Some(StorageConfig::S3(ref s3)) = site.storage {
+                s3.validate()?;
+            }
+            if let Some(ref cors) = site.cors {
+                cors.validate()?;
+            }
+            if let Some(ref rate_limit) = site.rate_limit {
+                rate_limit.validate()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn aws_client(&self) -> AwsClientConfig {
+        AwsClientConfig {
+            connect_timeout: self.aws_connect_timeout_ms.map(Duration::from_millis),
+            read_timeout: self.aws_read_timeout_ms.map(Duration::from_millis),
+            operation_timeout: self.aws_operation_timeout_ms.map(Duration::from_millis),
+            retry_mode: self.aws_retry_mode,
+            max_attempts: self.aws_max_attempts,
+            pool_max_idle_per_host: self.aws_pool_max_idle_per_host,
+            pool_idle_timeout: self.aws_pool_idle_timeout_ms.map(Duration::from_millis),
+            sts_endpoint_url: self.sts_endpoint_url.clone(),
+        }
+    }
+
+    /// S3 connection settings from the `GW_S3_*` variables, used where a site leaves them unset.
+    pub fn s3(&self) -> S3StorageConfig {
+        S3StorageConfig {
+            endpoint_url: self.s3_endpoint_url.clone(),
+            force_path_style: self.s3_force_path_style,
+            region: self.s3_region.clone(),
+            access_key_id: self.s3_access_key_id.clone(),
+            secret_access_key: self.s3_secret_access_key.clone(),
+            session_token: self.s3_session_token.clone(),
+            profile: self.s3_profile.clone(),
+            role_arn: self.s3_role_arn.clone(),
+            external_id: self.s3_external_id.clone(),
+            role_session_name: self.s3_role_session_name.clone(),
+        }
+    }
+
+    fn storage_or_default(&self, storage: Option<StorageConfig>) -> StorageConfig {
+        match storage.unwrap_or_else(|| self.storage.clone()) {
+            StorageConfig::S3(s3) => StorageConfig::S3(s3.or(&self.s3())),
+            storage => storage,
+        }
+    }
+
+    pub fn bucket_owner_cache(&self) -> BucketOwnerCacheConfig {
+        BucketOwnerCacheConfig {
+            ttl: Duration::from_millis(self.bucket_owner_cache_ttl_ms),
+            negative_ttl: Duration::from_millis(self.bucket_owner_negative_cache_ttl_ms),
+        }
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn concurrency(&self) -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            max_connections: self.max_connections,
+            max_in_flight_per_host: self.max_in_flight_per_host,
+            s3_max_concurrency: self.s3_max_concurrency,
+            load_shed_wait: Duration::from_millis(self.load_shed_wait_ms),
+        }
+    }
+
+    /// Connection limits. A zero value turns a limit off.
+    pub fn connection(&self) -> ConnectionConfig {
+        let millis = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+        let limit = |value: usize| (value > 0).then_some(value);
+        ConnectionConfig {
+            header_read_timeout: millis(self.header_read_timeout_ms),
+            keep_alive_timeout: millis(self.keep_alive_timeout_ms),
+            response_write_timeout: millis(self.response_write_timeout_ms),
+            max_header_bytes: limit(self.max_header_bytes),
+            max_headers: limit(self.max_headers),
+            max_uri_length: limit(self.max_uri_length),
+        }
+    }
+
+    /// Settings used for hosts that do not match any `sites` entry.
+    pub fn default_site(&self) -> SiteConfig {
+        SiteConfig {
+            root_object: self.root_object.clone(),
+            subdir_root_object: self.subdir_root_object.clone(),
+            no_such_key_redirect_object: self.no_such_key_redirect_object.clone(),
+            storage: Some(self.storage_or_default(None)),
+            allowed_bucket_owners: self.allowed_bucket_owners.clone(),
+            allowed_ips: self.allowed_ips.clone(),
+            denied_ips: self.denied_ips.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// `sites` entries, with unset index and redirect objects and storage taken from the top level settings.
+    pub fn sites(&self) -> Vec<SiteConfig> {
+        self.sites
+            .iter()
+            .cloned()
+            .map(|mut site| {
+                site.root_object = site.root_object.or(self.root_object.clone());
+                site.subdir_root_object =
+                    site.subdir_root_object.or(self.subdir_root_object.clone());
+                site.no_such_key_redirect_object = site
+                    .no_such_key_redirect_object
+                    .or(self.no_such_key_redirect_object.clone());
+                site.storage = Some(self.storage_or_default(site.storage));
+                if site.allowed_bucket_owners.is_empty() {
+                    site.allowed_bucket_owners = self.allowed_bucket_owners.clone();
+                }
+                if site.allowed_ips.is_empty() && site.denied_ips.is_empty() {
+                    site.allowed_ips = self.allowed_ips.clone();
+                    site.denied_ips = self.denied_ips.clone();
+                }
+                site
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_s3_storage_config_or() {
+        let defaults = S3StorageConfig {
+            endpoint_url: Some("http://localhost:9000".to_string()),
+            force_path_style: Some(true),
+            region: Some("us-east-1".to_string()),
+            ..Default::default()
+        };
+        let settings = S3StorageConfig {
+            region: Some("auto".to_string()),
+            ..Default::default()
+        }
+        .or(&defaults);
+
+        assert_eq!(
+            settings.endpoint_url.as_deref(),
+            Some("http://localhost:9000")
+        );
+        assert_eq!(settings.force_path_style, Some(true));
+        assert_eq!(settings.region.as_deref(), Some("auto"));
+    }
+
+    #[test_case(None, vec![], false, vec!["012345678901"]; "aws s3")]
+    #[test_case(None, vec![], true, vec![]; "cross account")]
+    #[test_case(Some("arn:aws:iam::123456789012:role/gateway"), vec![], false, vec!["123456789012"]; "assumed role")]
+    #[test_case(Some("arn:aws:iam::123456789012:role/gateway"), vec![], true, vec![]; "assumed role with cross account")]
+    #[test_case(None, vec!["111111111111", "222222222222"], false, vec!["111111111111", "222222222222"]; "allow list")]
+    #[test_case(None, vec!["111111111111"], true, vec!["111111111111"]; "allow list with cross account")]
+    fn test_expected_bucket_owners(
+        role_arn: Option<&str>,
+        allowed_bucket_owners: Vec<&str>,
+        allow_cross_account: bool,
+        expected: Vec<&str>,
+    ) {
+        let site = SiteConfig {
+            storage: Some(StorageConfig::S3(S3StorageConfig {
+                role_arn: role_arn.map(str::to_string),
+                ..Default::default()
+            })),
+            allowed_bucket_owners: allowed_bucket_owners
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            site.expected_bucket_owners(Some("012345678901"), allow_cross_account),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_expected_bucket_owners_without_owner() {
+        let site = SiteConfig {
+            storage: Some(StorageConfig::S3(S3StorageConfig {
+                endpoint_url: Some("http://localhost:9000".to_string()),
+                ..Default::default()
+            })),
+            allowed_bucket_owners: vec!["111111111111".to_string()],
+            ..Default::default()
+        };
+        assert!(site
+            .expected_bucket_owners(Some("012345678901"), false)
+            .is_empty());
+        assert!(!site.needs_self_account_id());
+
+        let site = SiteConfig {
+            storage: Some(StorageConfig::Filesystem {
+                root: PathBuf::from("/srv/www"),
+            }),
+            ..Default::default()
+        };
+        assert!(site
+            .expected_bucket_owners(Some("012345678901"), false)
+            .is_empty());
+        assert!(!site.needs_self_account_id());
+        assert!(SiteConfig::default().needs_self_account_id());
+    }
+
+    #[test]
+    fn test_secret_debug() {
+        let secret = Secret("minioadmin".to_string());
+        assert_eq!(format!("{:?}", secret), "Secret(***)");
+        assert_eq!(secret.expose(), "minioadmin");
+    }
+
+    #[test_case(Some("minioadmin"), Some("minioadmin"), true; "key pair")]
+    #[test_case(None, None, true; "default chain")]
+    #[test_case(Some("minioadmin"), None, false; "access key id only")]
+    #[test_case(None, Some("minioadmin"), false; "secret only")]
+    fn test_s3_storage_config_validate(
+        access_key_id: Option<&str>,
+        secret_access_key: Option<&str>,
+        valid: bool,
+    ) {
+        let settings = S3StorageConfig {
+            access_key_id: access_key_id.map(str::to_string),
+            secret_access_key: secret_access_key.map(|secret| Secret(secret.to_string())),
+            ..Default::default()
+        };
+        assert_eq!(settings.validate().is_ok(), valid);
+    }
+
+    #[test_case(RateLimitKey::Ip, None, &[], 10.0, 20, true; "ip")]
+    #[test_case(RateLimitKey::Header, Some("X-Api-Key"), &["partner"], 10.0, 20, true; "header values")]
+    #[test_case(RateLimitKey::Header, Some("X-Api-Key"), &[], 10.0, 20, false; "any header value")]
+    #[test_case(RateLimitKey::Header, None, &["partner"], 10.0, 20, false; "no header")]
+    #[test_case(RateLimitKey::Ip, None, &[], 0.0, 20, false; "zero rate")]
+    #[test_case(RateLimitKey::Ip, None, &[], f64::NAN, 20, false; "nan rate")]
+    #[test_case(RateLimitKey::Ip, None, &[], 10.0, 0, false; "zero burst")]
+    fn test_rate_limit_config_validate(
+        key: RateLimitKey,
+        header: Option<&str>,
+        header_values: &[&str],
+        requests_per_second: f64,
+        burst: u32,
+        valid: bool,
+    ) {
+        let settings = RateLimitConfig {
+            key,
+            header: header.map(str::to_string),
+            header_values: header_values
+                .iter()
+                .map(|value| Secret(value.to_string()))
+                .collect(),
+            requests_per_second,
+            burst,
+            max_keys: default_rate_limit_max_keys(),
+        };
+        assert_eq!(settings.validate().is_ok(), valid);
+    }
+
+    #[test_case(vec!["https://app.example.com"], true, true; "credentials for an origin")]
+    #[test_case(vec!["*"], false, true; "any origin")]
+    #[test_case(vec!["https://app.example.com", "*"], true, false; "credentials for any origin")]
+    fn test_cors_config_validate(allowed_origins: Vec<&str>, allow_credentials: bool, valid: bool) {
+        let settings = CorsConfig {
+            rules: vec![CorsRule {
+                allowed_origins: allowed_origins.into_iter().map(str::to_string).collect(),
+                allow_credentials,
+                ..Default::default()
+            }],
+            from_bucket: false,
+            refresh_interval_ms: default_cors_refresh_interval_ms(),
+        };
+        assert_eq!(settings.validate().is_ok(), valid);
+    }
+
+    #[test_case(vec![], vec![], Some("192.0.2.1"), true; "no rules")]
+    #[test_case(vec![], vec![], None, true; "no rules and unknown address")]
+    #[test_case(vec!["10.0.0.0/8"], vec![], Some("10.1.2.3"), true; "allowed")]
+    #[test_case(vec!["10.0.0.0/8"], vec![], Some("192.0.2.1"), false; "not allowed")]
+    #[test_case(vec!["10.0.0.0/8"], vec!["10.0.0.1"], Some("10.0.0.1"), false; "denied wins")]
+    #[test_case(vec![], vec!["2001:db8::/32"], Some("2001:db8::1"), false; "denied ipv6")]
+    #[test_case(vec!["192.0.2.0/24"], vec![], Some("::ffff:192.0.2.1"), true; "ipv4 mapped")]
+    #[test_case(vec!["10.0.0.0/8"], vec![], None, false; "unknown address")]
+    fn test_site_config_allows_ip(
+        allowed_ips: Vec<&str>,
+        denied_ips: Vec<&str>,
+        client_ip: Option<&str>,
+        expected: bool,
+    ) {
+        let cidrs = |cidrs: Vec<&str>| {
+            cidrs
+                .into_iter()
+                .map(|cidr| Cidr::try_from(cidr.to_string()).unwrap())
+                .collect()
+        };
+        let site = SiteConfig {
+            allowed_ips: cidrs(allowed_ips),
+            denied_ips: cidrs(denied_ips),
+            ..Default::default()
+        };
+        assert_eq!(
+            site.allows_ip(client_ip.map(|ip| ip.parse().unwrap())),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_cidr_invalid() {
+        assert!(Cidr::try_from("10.0.0.0/33".to_string()).is_err());
+        assert!(Cidr::try_from("localhost".to_string()).is_err());
+    }
+
+    #[test_case(200, true; "ok")]
+    #[test_case(404, true; "not found")]
+    #[test_case(301, false; "redirect")]
+    #[test_case(503, false; "server error")]
+    #[test_case(1000, false; "out of range")]
+    fn test_fallback_status(status: u16, valid: bool) {
+        assert_eq!(FallbackStatus::try_from(status).is_ok(), valid);
+    }
+
+    #[test_case(301, true; "moved permanently")]
+    #[test_case(308, true; "permanent redirect")]
+    #[test_case(304, false; "not modified")]
+    #[test_case(200, false; "ok")]
+    fn test_redirect_status(status: u16, valid: bool) {
+        assert_eq!(RedirectStatus::try_from(status).is_ok(), valid);
+    }
+
+    #[test_case(200, true; "ok")]
+    #[test_case(503, true; "service unavailable")]
+    #[test_case(101, false; "switching protocols")]
+    #[test_case(302, false; "redirect")]
+    #[test_case(600, false; "out of range")]
+    fn test_respond_status(status: u16, valid: bool) {
+        assert_eq!(RespondStatus::try_from(status).is_ok(), valid);
+    }
+
+    #[test]
+    fn test_rewrite_action_invalid_header_name() {
+        let action = serde_json::from_str::<RewriteAction>(
+            r#"{"type": "headers", "headers": {"X Page": "index"}}"#,
+        );
+        assert!(action.is_err());
+    }
+
+    #[test_case("*.html", "index.html", true; "extension")]
+    #[test_case("*.html", "docs/index.html", false; "star within segment")]
+    #[test_case("assets/**/*.js", "assets/app.js", true; "globstar without directory")]
+    #[test_case("assets/**/*.js", "assets/js/vendor/app.js", true; "globstar")]
+    #[test_case("assets/*.[0-9a-f][0-9a-f].js", "assets/app.3f.js", true; "character class")]
+    #[test_case("assets/*.[!0-9].js", "assets/app.3.js", false; "negated character class")]
+    #[test_case("*.{css,js}", "app.css", true; "alternation")]
+    #[test_case("a+b?.txt", "a+bc.txt", true; "regex characters")]
+    fn test_glob_regex(glob: &str, key: &str, expected: bool) {
+        let re = Regex::new(&glob_regex(glob)).unwrap();
+        assert_eq!(re.is_match(key), expected);
     }
 }
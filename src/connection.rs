@@ -0,0 +1,303 @@
+use crate::config::ConnectionConfig;
+use crate::response::{self, ResponseError};
+use bytes::Bytes;
+use futures_util::future::{self, Either};
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::{service_fn, Service};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Smallest read buffer hyper accepts.
+const MIN_MAX_HEADER_BYTES: usize = 8192;
+
+/// Serves the HTTP/1 requests of an accepted connection within the limits of `config`.
+pub async fn serve<T, S>(stream: T, svc: S, config: &ConnectionConfig) -> Result<(), hyper::Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response<Full<Bytes>>> + Send + 'static,
+    S::Error: From<ResponseError> + Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    S::Future: Send + 'static,
+{
+    let activity = Arc::new(Activity::new());
+    let io = TokioIo::new(TimeoutIo::new(
+        stream,
+        config.response_write_timeout,
+        activity.clone(),
+    ));
+    let max_uri_length = config.max_uri_length;
+    let request_activity = activity.clone();
+    let svc = service_fn(move |req: Request<Incoming>| {
+        let in_flight = InFlight::new(request_activity.clone());
+        let resp = if max_uri_length.is_some_and(|max| uri_length(&req) > max) {
+            tracing::info!("rejected request uri longer than {:?}", max_uri_length);
+            Either::Left(future::ready(
+                response::easy_response(StatusCode::URI_TOO_LONG).map_err(S::Error::from),
+            ))
+        } else {
+            Either::Right(svc.call(req))
+        };
+        async move {
+            let _in_flight = in_flight;
+            resp.await
+        }
+    });
+
+    let mut builder = http1::Builder::new();
+    builder
+        .timer(TokioTimer::new())
+        .header_read_timeout(config.header_read_timeout);
+    if let Some(max_headers) = config.max_headers {
+        builder.max_headers(max_headers);
+    }
+    if let Some(max_header_bytes) = config.max_header_bytes {
+        builder.max_buf_size(max_header_bytes.max(MIN_MAX_HEADER_BYTES));
+    }
+    let conn = builder.serve_connection(io, svc);
+    tokio::pin!(conn);
+
+    let Some(keep_alive_timeout) = config.keep_alive_timeout else {
+        return conn.await;
+    };
+    loop {
+        let deadline = activity
+            .idle_deadline(keep_alive_timeout)
+            .unwrap_or_else(|| Instant::now() + keep_alive_timeout);
+        tokio::select! {
+            result = conn.as_mut() => return result,
+            _ = tokio::time::sleep_until(deadline) => {
+                if activity
+                    .idle_deadline(keep_alive_timeout)
+                    .is_some_and(|deadline| deadline <= Instant::now())
+                {
+                    // Closes the idle connection, or lets a request that just started finish first.
+                    conn.as_mut().graceful_shutdown();
+                    return conn.await;
+                }
+            }
+        }
+    }
+}
+
+/// Length of the request target, e.g. the path and query of an origin-form request.
+fn uri_length<B>(req: &Request<B>) -> usize {
+    let uri = req.uri();
+    uri.scheme_str().map_or(0, |scheme| scheme.len() + 3)
+        + uri
+            .authority()
+            .map_or(0, |authority| authority.as_str().len())
+        + uri
+            .path_and_query()
+            .map_or(0, |path_and_query| path_and_query.as_str().len())
+}
+
+#[derive(Debug)]
+struct ActivityState {
+    in_flight: usize,
+    last_active_at: Instant,
+}
+
+/// When a connection last read a request or finished writing a response, and how many requests it is handling.
+#[derive(Debug)]
+struct Activity {
+    state: Mutex<ActivityState>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(ActivityState {
+                in_flight: 0,
+                last_active_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn touch(&self) {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .last_active_at = Instant::now();
+    }
+
+    /// When the connection will have been idle for `timeout`. `None` while a request is in flight.
+    fn idle_deadline(&self, timeout: Duration) -> Option<Instant> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        (state.in_flight == 0).then(|| state.last_active_at + timeout)
+    }
+}
+
+/// Marks a request as in flight until dropped.
+struct InFlight(Arc<Activity>);
+
+impl InFlight {
+    fn new(activity: Arc<Activity>) -> Self {
+        activity
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .in_flight += 1;
+        Self(activity)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight -= 1;
+        state.last_active_at = Instant::now();
+    }
+}
+
+/// A connection stream that fails a response not flushed within `write_timeout` of its first write,
+/// which also records reads and flushes as activity.
+struct TimeoutIo<T> {
+    io: T,
+    write_timeout: Option<Duration>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    /// Whether bytes were written since the last flush.
+    written: bool,
+    activity: Arc<Activity>,
+}
+
+impl<T> TimeoutIo<T> {
+    fn new(io: T, write_timeout: Option<Duration>, activity: Arc<Activity>) -> Self {
+        Self {
+            io,
+            write_timeout,
+            write_deadline: None,
+            written: false,
+            activity,
+        }
+    }
+
+    /// Starts the write deadline on the first write of a response and fails a pending write once it passed.
+    fn check_write_deadline<R>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<R>>,
+    ) -> Poll<io::Result<R>> {
+        self.written |= poll.is_ready();
+        let Some(write_timeout) = self.write_timeout else {
+            return poll;
+        };
+        let deadline = self
+            .write_deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(write_timeout)));
+        if poll.is_pending() && deadline.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("response not written within {:?}", write_timeout),
+            )));
+        }
+        poll
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TimeoutIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.io).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            this.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TimeoutIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write(cx, buf);
+        this.check_write_deadline(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write_vectored(cx, bufs);
+        this.check_write_deadline(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_flush(cx);
+        if matches!(poll, Poll::Ready(Ok(()))) {
+            // hyper flushes whenever the connection is polled, so only a flush after a write is activity.
+            if std::mem::take(&mut this.written) {
+                this.activity.touch();
+            }
+            this.write_deadline = None;
+            return poll;
+        }
+        this.check_write_deadline(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+    use tokio::io::AsyncWriteExt;
+
+    #[test_case("/index.html", 11; "origin form")]
+    #[test_case("/search?q=gateway", 17; "query")]
+    #[test_case("http://foo.example.com/index.html", 33; "absolute form")]
+    fn test_uri_length(uri: &str, expected: usize) {
+        let req = Request::builder().uri(uri).body(()).unwrap();
+        assert_eq!(uri_length(&req), expected);
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        // The client never reads, so the response does not fit the pipe.
+        let (_client, server) = tokio::io::duplex(16);
+        let activity = Arc::new(Activity::new());
+        let mut io = TimeoutIo::new(server, Some(Duration::from_millis(20)), activity);
+
+        let e = io.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_idle_deadline() {
+        let activity = Arc::new(Activity::new());
+        let timeout = Duration::from_secs(60);
+        assert!(activity.idle_deadline(timeout).is_some());
+
+        let in_flight = InFlight::new(activity.clone());
+        assert!(activity.idle_deadline(timeout).is_none());
+
+        drop(in_flight);
+        assert!(activity.idle_deadline(timeout).unwrap() > Instant::now());
+    }
+}
@@ -0,0 +1,483 @@
+use crate::bucket_cache::BucketCache;
+use crate::config::{CorsConfig, CorsRule};
+use crate::site::Site;
+use crate::storage::StorageError;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::Method;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Shortest time before rules that failed to load are read again, so that a throttled or failing bucket is
+/// retried soon without being read on every request.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+struct CachedRules {
+    rules: Arc<Vec<CorsRule>>,
+    reload_at: Instant,
+}
+
+/// The rules read from a bucket. `reload` is held while they are read, so concurrent requests share one read.
+#[derive(Debug, Default)]
+struct BucketRules {
+    cached: RwLock<Option<CachedRules>>,
+    reload: tokio::sync::Mutex<()>,
+}
+
+impl BucketRules {
+    fn cached(&self) -> Option<CachedRules> {
+        self.cached
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn fresh(&self) -> Option<Arc<Vec<CorsRule>>> {
+        self.cached()
+            .filter(|cached| cached.reload_at > Instant::now())
+            .map(|cached| cached.rules)
+    }
+}
+
+/// The CORS rules of a site, either configured or read from each bucket and reused for the refresh interval.
+#[derive(Debug)]
+pub struct Cors {
+    rules: Arc<Vec<CorsRule>>,
+    from_bucket: bool,
+    refresh_interval: Duration,
+    retry_interval: Duration,
+    buckets: BucketCache<Arc<BucketRules>>,
+}
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> Self {
+        Self {
+            rules: Arc::new(config.rules),
+            from_bucket: config.from_bucket,
+            refresh_interval: Duration::from_millis(config.refresh_interval_ms),
+            retry_interval: RETRY_INTERVAL,
+            buckets: BucketCache::new(),
+        }
+    }
+
+    /// The rules for a bucket of the site. Bucket rules that fail to load keep the previous ones, or none,
+    /// until they are retried after the retry interval. While they are read again, other requests get the previous ones, or wait for the first read.
+    pub async fn rules(&self, site: &Site, bucket: &str) -> Arc<Vec<CorsRule>> {
+        if !self.from_bucket {
+            return self.rules.clone();
+        }
+
+        let entry = self.buckets.get_or_insert_with(bucket, Default::default);
+        if let Some(rules) = entry.fresh() {
+            return rules;
+        }
+        let stale = entry.cached().map(|cached| cached.rules);
+        let _reload = match (entry.reload.try_lock(), stale) {
+            (Ok(reload), _) => reload,
+            (Err(_), Some(stale)) => return stale,
+            (Err(_), None) => entry.reload.lock().await,
+        };
+        // Read by the request this one waited for.
+        if let Some(rules) = entry.fresh() {
+            return rules;
+        }
+
+        let (rules, reload_in) = match self.load(site, bucket).await {
+            Ok(rules) => (Arc::new(rules), self.refresh_interval),
+            Err(e) => {
+                tracing::warn!("failed to get bucket cors: {}: {}", bucket, e);
+                let previous = entry.cached().map(|cached| cached.rules);
+                (
+                    previous.unwrap_or_default(),
+                    self.retry_interval.min(self.refresh_interval),
+                )
+            }
+        };
+        *entry.cached.write().unwrap_or_else(|e| e.into_inner()) = Some(CachedRules {
+            rules: rules.clone(),
+            reload_at: Instant::now() + reload_in,
+        });
+        rules
+    }
+
+    /// The rules for a bucket without reading it: the configured ones, or the bucket's rules read so far.
+    pub fn cached_rules(&self, bucket: &str) -> Arc<Vec<CorsRule>> {
+        if !self.from_bucket {
+            return self.rules.clone();
+        }
+        self.buckets
+            .get(bucket)
+            .and_then(|entry| entry.cached())
+            .map(|cached| cached.rules)
+            .unwrap_or_default()
+    }
+
+    async fn load(&self, site: &Site, bucket: &str) -> Result<Vec<CorsRule>, StorageError> {
+        let owner = site.expected_owner(bucket).await?;
+        site.storage.get_bucket_cors(bucket, owner.as_deref()).await
+    }
+}
+
+/// Whether the request is a CORS preflight: an `OPTIONS` with an origin and the method it asks for.
+pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::OPTIONS
+        && headers.contains_key(ORIGIN)
+        && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Headers answering a preflight, or `None` when no rule allows the origin, method and headers asked for.
+pub fn preflight_headers(rules: &[CorsRule], headers: &HeaderMap) -> Option<HeaderMap> {
+    let origin = header_str(headers, &ORIGIN)?;
+    let method = header_str(headers, &ACCESS_CONTROL_REQUEST_METHOD)?;
+    let request_headers = header_str(headers, &ACCESS_CONTROL_REQUEST_HEADERS)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .collect::<Vec<&str>>()
+        })
+        .unwrap_or_default();
+    let rule = rules.iter().find(|rule| {
+        allows(rule, origin, method)
+            && request_headers.iter().all(|name| {
+                rule.allowed_headers
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, name))
+            })
+    })?;
+
+    let mut resp_headers = origin_headers(rule, origin)?;
+    resp_headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_str(&rule.allowed_methods.join(", ")).ok()?,
+    );
+    if !request_headers.is_empty() {
+        resp_headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_str(&request_headers.join(", ")).ok()?,
+        );
+    }
+    if let Some(max_age_secs) = rule.max_age_secs {
+        resp_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age_secs));
+    }
+    resp_headers.insert(
+        VARY,
+        HeaderValue::from_static("Access-Control-Request-Method, Access-Control-Request-Headers"),
+    );
+    Some(resp_headers)
+}
+
+/// Headers added to the response of a cross-origin request that a rule allows.
+pub fn response_headers(rules: &[CorsRule], method: &Method, headers: &HeaderMap) -> HeaderMap {
+    let Some(origin) = header_str(headers, &ORIGIN) else {
+        return HeaderMap::new();
+    };
+    let Some(rule) = rules
+        .iter()
+        .find(|rule| allows(rule, origin, method.as_str()))
+    else {
+        return HeaderMap::new();
+    };
+
+    let mut resp_headers = origin_headers(rule, origin).unwrap_or_default();
+    if !rule.expose_headers.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&rule.expose_headers.join(", ")) {
+            resp_headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, value);
+        }
+    }
+    resp_headers
+}
+
+fn allows(rule: &CorsRule, origin: &str, method: &str) -> bool {
+    rule.allowed_origins
+        .iter()
+        .any(|pattern| wildcard_match(pattern, origin))
+        && rule
+            .allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+}
+
+/// `Access-Control-Allow-Origin` and credentials. A rule for any origin answers `*` and never allows credentials,
+/// including one read from a bucket.
+fn origin_headers(rule: &CorsRule, origin: &str) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    if rule.allowed_origins.iter().any(|pattern| pattern == "*") {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        return Some(headers);
+    }
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_str(origin).ok()?,
+    );
+    if rule.allow_credentials {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    Some(headers)
+}
+
+/// Case-insensitive match with at most one `*` standing for any characters.
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let (pattern, value) = (pattern.to_ascii_lowercase(), value.to_ascii_lowercase());
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            value.len() >= prefix.len() + suffix.len()
+                && value.starts_with(prefix)
+                && value.ends_with(suffix)
+        }
+        None => pattern == value,
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SiteConfig;
+    use crate::storage::{GetObjectResult, HeadObjectResult, Storage};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_case::test_case;
+
+    fn rules() -> Vec<CorsRule> {
+        vec![
+            CorsRule {
+                allowed_origins: vec!["https://*.example.com".to_string()],
+                allowed_methods: vec!["GET".to_string(), "HEAD".to_string()],
+                allowed_headers: vec!["Authorization".to_string(), "X-Amz-*".to_string()],
+                expose_headers: vec!["ETag".to_string()],
+                max_age_secs: Some(3600),
+                allow_credentials: true,
+            },
+            CorsRule {
+                allowed_origins: vec!["*".to_string()],
+                allowed_methods: vec!["GET".to_string()],
+                ..Default::default()
+            },
+        ]
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test_case("https://*.example.com", "https://app.example.com", true; "wildcard")]
+    #[test_case("https://*.example.com", "https://example.com", false; "wildcard without subdomain")]
+    #[test_case("https://app.example.com", "HTTPS://APP.EXAMPLE.COM", true; "case insensitive")]
+    #[test_case("*", "http://localhost:3000", true; "any")]
+    #[test_case("https://app.example.com", "https://app.example.com.evil.net", false; "exact")]
+    fn test_wildcard_match(pattern: &str, value: &str, expected: bool) {
+        assert_eq!(wildcard_match(pattern, value), expected);
+    }
+
+    #[test]
+    fn test_preflight_headers() {
+        let req_headers = headers(&[
+            ("origin", "https://app.example.com"),
+            ("access-control-request-method", "GET"),
+            (
+                "access-control-request-headers",
+                "authorization, x-amz-date",
+            ),
+        ]);
+        assert!(is_preflight(&Method::OPTIONS, &req_headers));
+
+        let resp_headers = preflight_headers(&rules(), &req_headers).unwrap();
+        assert_eq!(
+            resp_headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(resp_headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, HEAD");
+        assert_eq!(
+            resp_headers[ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization, x-amz-date"
+        );
+        assert_eq!(resp_headers[ACCESS_CONTROL_MAX_AGE], "3600");
+        assert_eq!(resp_headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+
+    #[test_case("https://app.example.com", "PUT", ""; "method not allowed")]
+    #[test_case("https://app.example.com", "GET", "x-api-key"; "header not allowed")]
+    #[test_case("http://localhost:3000", "GET", "authorization"; "header not allowed for any origin")]
+    fn test_preflight_headers_rejected(
+        origin: &'static str,
+        method: &'static str,
+        request_headers: &'static str,
+    ) {
+        let req_headers = headers(&[
+            ("origin", origin),
+            ("access-control-request-method", method),
+            ("access-control-request-headers", request_headers),
+        ]);
+        assert!(preflight_headers(&rules(), &req_headers).is_none());
+    }
+
+    #[test_case("https://app.example.com", Some("https://app.example.com"), Some("ETag"); "credentials")]
+    #[test_case("http://localhost:3000", Some("*"), None; "any origin")]
+    #[test_case("", None, None; "no origin")]
+    fn test_response_headers(
+        origin: &'static str,
+        expected_origin: Option<&str>,
+        expected_expose: Option<&str>,
+    ) {
+        let req_headers = match origin {
+            "" => HeaderMap::new(),
+            origin => headers(&[("origin", origin)]),
+        };
+        let resp_headers = response_headers(&rules(), &Method::GET, &req_headers);
+        let get = |name| {
+            resp_headers
+                .get(name)
+                .map(|value: &HeaderValue| value.to_str().unwrap())
+        };
+
+        assert_eq!(get(ACCESS_CONTROL_ALLOW_ORIGIN), expected_origin);
+        assert_eq!(get(ACCESS_CONTROL_EXPOSE_HEADERS), expected_expose);
+    }
+
+    #[test]
+    fn test_response_headers_any_origin_credentials() {
+        let rules = vec![CorsRule {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        }];
+        let resp_headers = response_headers(
+            &rules,
+            &Method::GET,
+            &headers(&[("origin", "https://evil.example.net")]),
+        );
+        assert_eq!(resp_headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!resp_headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+
+    #[derive(Debug, Default)]
+    struct BucketCors {
+        calls: AtomicUsize,
+        /// Reads failing before the first success.
+        failures: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for BucketCors {
+        async fn get_object(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _version_id: Option<&str>,
+        ) -> Result<GetObjectResult, StorageError> {
+            Err(StorageError::NoSuchKey)
+        }
+
+        async fn head_object(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _version_id: Option<&str>,
+        ) -> Result<HeadObjectResult, StorageError> {
+            Err(StorageError::NoSuchKey)
+        }
+
+        async fn head_bucket(
+            &self,
+            _bucket: &str,
+            _expected_bucket_owner: &str,
+        ) -> Result<(), StorageError> {
+            Ok(())
+        }
+
+        async fn get_bucket_cors(
+            &self,
+            bucket: &str,
+            _expected_bucket_owner: Option<&str>,
+        ) -> Result<Vec<CorsRule>, StorageError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(StorageError::Throttled("SlowDown".to_string()));
+            }
+            Ok(vec![CorsRule {
+                allowed_origins: vec![format!("https://{}", bucket)],
+                ..Default::default()
+            }])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rules_from_bucket() {
+        let storage = Arc::new(BucketCors::default());
+        let site = Site::new(SiteConfig::default(), storage.clone(), None);
+        let cors = Cors::new(CorsConfig {
+            rules: Vec::new(),
+            from_bucket: true,
+            refresh_interval_ms: 300_000,
+        });
+
+        assert!(cors.cached_rules("foo.example.com").is_empty());
+        assert_eq!(storage.calls.load(Ordering::Relaxed), 0);
+
+        let (rules, concurrent) = tokio::join!(
+            cors.rules(&site, "foo.example.com"),
+            cors.rules(&site, "foo.example.com")
+        );
+        assert_eq!(rules[0].allowed_origins, vec!["https://foo.example.com"]);
+        assert_eq!(concurrent, rules);
+        cors.rules(&site, "foo.example.com").await;
+        assert_eq!(cors.cached_rules("foo.example.com"), rules);
+        assert_eq!(storage.calls.load(Ordering::Relaxed), 1);
+
+        let rules = cors.rules(&site, "bar.example.com").await;
+        assert_eq!(rules[0].allowed_origins, vec!["https://bar.example.com"]);
+        assert_eq!(storage.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rules_from_bucket_retried() {
+        let storage = Arc::new(BucketCors {
+            failures: AtomicUsize::new(1),
+            ..Default::default()
+        });
+        let site = Site::new(SiteConfig::default(), storage.clone(), None);
+        let cors = Cors {
+            retry_interval: Duration::from_millis(50),
+            ..Cors::new(CorsConfig {
+                rules: Vec::new(),
+                from_bucket: true,
+                refresh_interval_ms: 300_000,
+            })
+        };
+
+        assert!(cors.rules(&site, "foo.example.com").await.is_empty());
+        assert!(cors.rules(&site, "foo.example.com").await.is_empty());
+        assert_eq!(storage.calls.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let rules = cors.rules(&site, "foo.example.com").await;
+        assert_eq!(rules[0].allowed_origins, vec!["https://foo.example.com"]);
+        assert_eq!(storage.calls.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,64 @@
+use regex::Regex;
+
+/// Domains such as `www.example.com` or `*.example.com`, compiled once. A `*` stands for one label, and
+/// malformed domains match nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Domains(Vec<Regex>);
+
+impl Domains {
+    pub fn new(domains: &[String]) -> Self {
+        let valid = Regex::new(r"^(\*\.)?([a-zA-Z0-9]+(-[a-zA-Z0-9]+)*\.)+[a-zA-Z]{2,}$")
+            .expect("domain pattern is valid");
+        let patterns = domains
+            .iter()
+            .filter(|domain| valid.is_match(domain))
+            .filter_map(|domain| {
+                let domain = domain.replace('*', r"([a-zA-Z0-9]+(-[a-zA-Z0-9]+)*)");
+                let domain = domain.replace('.', r"\.");
+                Regex::new(&format!("^{}$", domain)).ok()
+            })
+            .collect();
+        Self(patterns)
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        self.0.iter().any(|re| re.is_match(host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn domains(domains: Vec<&str>) -> Domains {
+        Domains::new(
+            &domains
+                .iter()
+                .map(|domain| domain.to_string())
+                .collect::<Vec<String>>(),
+        )
+    }
+
+    #[test_case(vec!["foo.example.com"], "foo.example.com"; "exact match")]
+    #[test_case(vec!["*.example.com"], "foo.example.com"; "wildcard match")]
+    #[test_case(vec!["*.bar.example.com"], "foo.bar.example.com"; "wildcard match with subdomain")]
+    fn test_matches_true(allow_domains: Vec<&str>, domain: &str) {
+        assert!(domains(allow_domains).matches(domain));
+    }
+
+    #[test_case(vec!["foo.example.com"], "bar.example.com"; "exact match")]
+    #[test_case(vec!["*.example.com"], "bar.example.net"; "wildcard match")]
+    #[test_case(vec!["*.example.com"], "foo.bar.example.net"; "wildcard match with nested subdomain")]
+    #[test_case(vec!["*.example.com"], "example.com"; "wildcard match with root domain")]
+    fn test_matches_false(allow_domains: Vec<&str>, domain: &str) {
+        assert!(!domains(allow_domains).matches(domain));
+    }
+
+    #[test_case(vec!["*example.com"], "foo.example.com"; "invalid wildcard match")]
+    #[test_case(vec!["*.*.example.com"], "foo.bar.example.com"; "invalid wildcard match with nested subdomain")]
+    #[test_case(vec!["hoge.example.*"], "foo.example.net"; "invalid wildcard match with top level domain")]
+    fn test_matches_invalid(allow_domains: Vec<&str>, domain: &str) {
+        assert!(!domains(allow_domains).matches(domain));
+    }
+}
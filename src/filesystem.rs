@@ -0,0 +1,146 @@
+use crate::storage::{self, GetObjectResult, HeadObjectResult, Storage, StorageError};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// Serves objects from `<root>/<bucket>/<key>`.
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    root: PathBuf,
+}
+
+impl Filesystem {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn object_path(&self, bucket: &str, key: &str) -> Result<PathBuf, StorageError> {
+        let bucket_path = self.bucket_path(bucket)?;
+        let key = Path::new(key);
+        if !key
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(StorageError::NoSuchKey);
+        }
+
+        Ok(bucket_path.join(key))
+    }
+
+    fn bucket_path(&self, bucket: &str) -> Result<PathBuf, StorageError> {
+        let mut components = Path::new(bucket).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.root.join(bucket)),
+            _ => Err(StorageError::NotFound(format!(
+                "invalid bucket: {}",
+                bucket
+            ))),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for Filesystem {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        storage::unversioned(version_id)?;
+        let path = self.object_path(bucket, key)?;
+        match tokio::fs::read(&path).await {
+            Ok(body) => Ok(GetObjectResult::builder().body(body.into()).build()),
+            Err(e) => Err(io_error(&path, e)),
+        }
+    }
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        storage::unversioned(version_id)?;
+        let path = self.object_path(bucket, key)?;
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => Ok(HeadObjectResult::builder().build()),
+            Ok(_) => Err(StorageError::NoSuchKey),
+            Err(e) => Err(io_error(&path, e)),
+        }
+    }
+
+    async fn head_bucket(
+        &self,
+        bucket: &str,
+        _expected_bucket_owner: &str,
+    ) -> Result<(), StorageError> {
+        let path = self.bucket_path(bucket)?;
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => Err(StorageError::NotFound(format!(
+                "not a directory: {}",
+                path.display()
+            ))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(StorageError::NotFound(format!(
+                "no such directory: {}",
+                path.display()
+            ))),
+            Err(e) => Err(io_error(&path, e)),
+        }
+    }
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> StorageError {
+    match e.kind() {
+        // Reading a directory is treated like a missing key, as S3 has no directories.
+        ErrorKind::NotFound | ErrorKind::IsADirectory | ErrorKind::NotADirectory => {
+            StorageError::NoSuchKey
+        }
+        ErrorKind::PermissionDenied => {
+            StorageError::AccessDenied(format!("{}: {}", path.display(), e))
+        }
+        _ => StorageError::Internal(format!("{}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("index.html", true; "object")]
+    #[test_case("subdir1/index.html", true; "nested object")]
+    #[test_case("../secret", false; "parent directory")]
+    #[test_case("subdir1/../../secret", false; "nested parent directory")]
+    #[test_case("/etc/passwd", false; "absolute path")]
+    fn test_object_path(key: &str, expected: bool) {
+        let storage = Filesystem::new(PathBuf::from("/srv/www"));
+        assert_eq!(
+            storage.object_path("foo.example.com", key).is_ok(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_object() {
+        let storage = Filesystem::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests"));
+
+        let resp = storage
+            .get_object("data", "index.html", None)
+            .await
+            .unwrap();
+        assert_eq!(resp.body(), include_str!("../tests/data/index.html"));
+
+        let error = storage
+            .get_object("data", "subdir1", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, StorageError::NoSuchKey));
+
+        let error = storage
+            .get_object("data", "missing.html", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, StorageError::NoSuchKey));
+    }
+}
@@ -1,70 +1,399 @@
+use crate::release::Released;
 use crate::response;
 use crate::response::ResponseError;
-use crate::s3::S3;
+use crate::site::Site;
+use crate::storage::{ExpectedOwner, Storage, StorageError};
 use bytes::Bytes;
 use http_body_util::Full;
 use hyper::{Response, StatusCode};
 
+/// Seconds clients are asked to wait before retrying when the storage is throttling or unavailable.
+const RETRY_AFTER_SECS: u64 = 1;
+
 #[derive(Debug, thiserror::Error)]
 pub enum HandlerError {
     #[error("failed to respond: {0}")]
     Response(#[from] ResponseError),
 }
 
-pub async fn s3_handle<T>(
-    s3_client: &T,
-    no_such_key_redirect_object: Option<String>,
-    self_account_id: Option<String>,
+/// Serves the object of `key`, or of `fallback_key` when there is no object at `key`.
+pub async fn s3_handle(
+    site: &Site,
     bucket: &str,
     key: &str,
-) -> Result<Response<Full<Bytes>>, HandlerError>
-where
-    T: S3 + Send + Sync + 'static,
-{
-    tracing::info!("get object: s3://{}/{}", bucket, key);
-
-    if let Some(id) = self_account_id {
-        if let Err(e) = s3_client.head_bucket(bucket, &id).await {
-            tracing::warn!(
-                "failed to head bucket: bucket: {} e: {:?}",
-                bucket,
-                e.into_service_error()
-            );
-            return Ok(response::easy_response(StatusCode::FORBIDDEN)?);
-        }
+    fallback_key: Option<&str>,
+    accept: Option<&str>,
+    is_navigation: bool,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    tracing::info!("get object: {}/{}", bucket, key);
+
+    let owner = match site.expected_owner(bucket).await {
+        Ok(owner) => owner,
+        Err(e) => return failed_response(&e, "check bucket owner", bucket, accept),
+    };
+    let storage = &ExpectedOwner::new(site.storage.as_ref(), owner.as_deref());
+
+    let manifest = match site.release_manifests {
+        Some(ref release_manifests) => match release_manifests.manifest(storage, bucket).await {
+            Ok(manifest) => Some(manifest),
+            Err(e) => return failed_response(&e, "get release manifest", bucket, accept),
+        },
+        None => None,
+    };
+    let storage = &Released::new(storage, manifest.as_deref());
+    let site = &site.config;
+
+    let mut key = key;
+    let mut result = storage.get_object(bucket, key, None).await;
+    if let (Err(StorageError::NoSuchKey), Some(fallback_key)) = (&result, fallback_key) {
+        tracing::info!("get fallback object: {}/{}", bucket, fallback_key);
+        key = fallback_key;
+        result = storage.get_object(bucket, key, None).await;
     }
 
-    let resp = match s3_client.get_object(bucket, key).await {
+    let resp = match result {
         Ok(resp) => resp,
         Err(e) => {
-            let error = e.into_service_error();
+            let error = StorageErrorClass::classify(&e);
+            let mut resp = if matches!(e, StorageError::NoSuchKey) {
+                response::no_such_key_response(storage, bucket, site, accept, is_navigation).await?
+            } else {
+                response::error_document_response(storage, bucket, site, error.status, accept)
+                    .await?
+            };
+            // A missing key answered with the SPA fallback or a redirect is not a failure.
+            if !(resp.status().is_client_error() || resp.status().is_server_error()) {
+                tracing::info!(
+                    "no such key: {}/{}: served with {}",
+                    bucket,
+                    key,
+                    resp.status()
+                );
+                return Ok(resp);
+            }
             tracing::warn!(
-                "failed to get object: bucket: {} key: {} e: {:?}",
+                error_id = error.id,
+                "failed to get object: bucket: {} key: {} status: {} e: {}",
                 bucket,
                 key,
-                error,
+                resp.status(),
+                e,
             );
-            return Ok(response::s3_error_response(
-                s3_client,
-                bucket,
-                error.is_no_such_key(),
-                no_such_key_redirect_object,
-            )
-            .await?);
+            response::set_error_headers(&mut resp, &error.id, error.retry_after);
+            return Ok(resp);
         }
     };
 
-    let body = match resp.body().collect().await {
-        Ok(body) => body.into_bytes(),
-        Err(e) => {
-            tracing::error!("failed to collect body: {:?}", e);
-            return Ok(response::easy_response(StatusCode::INTERNAL_SERVER_ERROR)?);
-        }
-    };
+    if let Some(location) = resp.website_redirect_location() {
+        let location = response::website_redirect_target(key, location);
+        tracing::info!("website redirect: {}/{} -> {}", bucket, key, location);
+        return Ok(response::redirect_response(
+            StatusCode::MOVED_PERMANENTLY,
+            &location,
+        )?);
+    }
 
     let content_type = mime_guess::from_path(key)
         .first_or(mime::TEXT_PLAIN)
         .to_string();
 
-    Ok(response::s3_ok_response(content_type, body)?)
+    Ok(response::s3_ok_response(site, key, content_type, resp)?)
+}
+
+/// Serves the site's 404 error document for a request that resolves to no key.
+pub async fn s3_not_found(
+    site: &Site,
+    bucket: &str,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    let owner = match site.expected_owner(bucket).await {
+        Ok(owner) => owner,
+        Err(e) => return failed_response(&e, "check bucket owner", bucket, accept),
+    };
+    let storage = &ExpectedOwner::new(site.storage.as_ref(), owner.as_deref());
+
+    Ok(response::error_document_response(
+        storage,
+        bucket,
+        &site.config,
+        StatusCode::NOT_FOUND,
+        accept,
+    )
+    .await?)
+}
+
+/// Responds with the built-in error body for a failure before the object is read.
+fn failed_response(
+    e: &StorageError,
+    action: &str,
+    bucket: &str,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, HandlerError> {
+    let error = StorageErrorClass::classify(e);
+    tracing::warn!(
+        error_id = error.id,
+        "failed to {}: bucket: {} status: {} e: {}",
+        action,
+        bucket,
+        error.status,
+        e
+    );
+    let mut resp = response::error_response(error.status, accept)?;
+    response::set_error_headers(&mut resp, &error.id, error.retry_after);
+    Ok(resp)
+}
+
+/// The response a storage error is mapped to, with an id shared by the response and the log.
+#[derive(Debug)]
+pub struct StorageErrorClass {
+    pub id: String,
+    pub status: StatusCode,
+    pub retry_after: Option<u64>,
+}
+
+impl StorageErrorClass {
+    pub fn classify(error: &StorageError) -> Self {
+        let (status, retry_after) = match error {
+            StorageError::NoSuchKey | StorageError::NotFound(_) => (StatusCode::NOT_FOUND, None),
+            StorageError::AccessDenied(_) => (StatusCode::FORBIDDEN, None),
+            StorageError::Throttled(_) => (StatusCode::SERVICE_UNAVAILABLE, Some(RETRY_AFTER_SECS)),
+            StorageError::Timeout(_) => (StatusCode::GATEWAY_TIMEOUT, None),
+            StorageError::Upstream(_) => (StatusCode::BAD_GATEWAY, None),
+            StorageError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, None),
+        };
+
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            status,
+            retry_after,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_owner::BucketOwnerCheck;
+    use crate::config::{FallbackStatus, ReleaseManifestConfig, SiteConfig, SpaFallbackConfig};
+    use crate::memory::Memory;
+    use crate::storage::{GetObjectResult, HeadObjectResult};
+    use http_body_util::BodyExt;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use test_case::test_case;
+
+    #[derive(Debug)]
+    struct MockStorage {
+        get_object_error: StorageError,
+        head_bucket_error: Option<StorageError>,
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for MockStorage {
+        async fn get_object(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _version_id: Option<&str>,
+        ) -> Result<GetObjectResult, StorageError> {
+            Err(self.get_object_error.clone())
+        }
+
+        async fn head_object(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _version_id: Option<&str>,
+        ) -> Result<HeadObjectResult, StorageError> {
+            Err(StorageError::NoSuchKey)
+        }
+
+        async fn head_bucket(
+            &self,
+            _bucket: &str,
+            _expected_bucket_owner: &str,
+        ) -> Result<(), StorageError> {
+            match self.head_bucket_error {
+                Some(ref error) => Err(error.clone()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test_case(StorageError::NoSuchKey, StatusCode::NOT_FOUND, None; "no such key is 404")]
+    #[test_case(StorageError::NotFound("NoSuchBucket".to_string()), StatusCode::NOT_FOUND, None; "no such bucket is 404")]
+    #[test_case(StorageError::AccessDenied("AccessDenied".to_string()), StatusCode::FORBIDDEN, None; "access denied is 403")]
+    #[test_case(StorageError::Throttled("SlowDown".to_string()), StatusCode::SERVICE_UNAVAILABLE, Some("1"); "slow down is 503")]
+    #[test_case(StorageError::Timeout("operation timed out".to_string()), StatusCode::GATEWAY_TIMEOUT, None; "timeout is 504")]
+    #[test_case(StorageError::Upstream("connection refused".to_string()), StatusCode::BAD_GATEWAY, None; "connection refused is 502")]
+    #[test_case(StorageError::Internal("construction failure".to_string()), StatusCode::INTERNAL_SERVER_ERROR, None; "construction failure is 500")]
+    #[tokio::test]
+    async fn test_s3_handle_get_object_error(
+        get_object_error: StorageError,
+        expected_status: StatusCode,
+        expected_retry_after: Option<&str>,
+    ) {
+        let storage = MockStorage {
+            get_object_error,
+            head_bucket_error: None,
+        };
+        let site = Site::new(SiteConfig::default(), Arc::new(storage), None);
+        let resp = s3_handle(&site, "foo.example.com", "index.html", None, None, false)
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), expected_status);
+        assert!(resp.headers().contains_key("X-Error-Id"));
+        assert_eq!(
+            resp.headers()
+                .get("Retry-After")
+                .map(|value| value.to_str().unwrap()),
+            expected_retry_after
+        );
+    }
+
+    #[test_case(StorageError::AccessDenied("AccessDenied".to_string()), StatusCode::FORBIDDEN; "bucket owner mismatch is 403")]
+    #[test_case(StorageError::Throttled("SlowDown".to_string()), StatusCode::SERVICE_UNAVAILABLE; "throttled is 503")]
+    #[tokio::test]
+    async fn test_s3_handle_head_bucket_error(
+        head_bucket_error: StorageError,
+        expected_status: StatusCode,
+    ) {
+        let storage = MockStorage {
+            get_object_error: StorageError::NoSuchKey,
+            head_bucket_error: Some(head_bucket_error),
+        };
+        let bucket_owner =
+            BucketOwnerCheck::new(vec!["012345678901".to_string()], Default::default());
+        let site = Site::new(SiteConfig::default(), Arc::new(storage), Some(bucket_owner));
+        let resp = s3_handle(&site, "foo.example.com", "index.html", None, None, false)
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), expected_status);
+        assert!(resp.headers().contains_key("X-Error-Id"));
+    }
+
+    #[tokio::test]
+    async fn test_s3_not_found_bucket_owner_mismatch() {
+        let storage = MockStorage {
+            get_object_error: StorageError::NoSuchKey,
+            head_bucket_error: Some(StorageError::AccessDenied("AccessDenied".to_string())),
+        };
+        let bucket_owner =
+            BucketOwnerCheck::new(vec!["012345678901".to_string()], Default::default());
+        let site = Site::new(
+            SiteConfig {
+                error_documents: HashMap::from([(404, "404.html".to_string())]),
+                ..Default::default()
+            },
+            Arc::new(storage),
+            Some(bucket_owner),
+        );
+        let resp = s3_not_found(&site, "foo.example.com", None).await.unwrap();
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_s3_handle_memory() {
+        let storage = Memory::default();
+        storage.insert("foo.example.com", "index.html", "<h1>index</h1>", None);
+        storage.insert(
+            "foo.example.com",
+            "moved.html",
+            "",
+            Some("/index.html".to_string()),
+        );
+        let site = Site::new(SiteConfig::default(), Arc::new(storage), None);
+
+        let resp = s3_handle(&site, "foo.example.com", "index.html", None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["Content-Type"], mime::TEXT_HTML.as_ref());
+
+        let resp = s3_handle(&site, "foo.example.com", "moved.html", None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers()["Location"], "/index.html");
+    }
+
+    #[test_case(true, StatusCode::OK, false; "spa fallback")]
+    #[test_case(false, StatusCode::NOT_FOUND, true; "not a navigation")]
+    #[tokio::test]
+    async fn test_s3_handle_no_such_key_error_id(
+        is_navigation: bool,
+        expected_status: StatusCode,
+        expected_error_id: bool,
+    ) {
+        let storage = Memory::default();
+        storage.insert("foo.example.com", "index.html", "<h1>index</h1>", None);
+        let site = Site::new(
+            SiteConfig {
+                spa_fallback: Some(SpaFallbackConfig {
+                    object: "index.html".to_string(),
+                    status: FallbackStatus(StatusCode::OK),
+                }),
+                ..Default::default()
+            },
+            Arc::new(storage),
+            None,
+        );
+        let resp = s3_handle(
+            &site,
+            "foo.example.com",
+            "app/settings",
+            None,
+            None,
+            is_navigation,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(resp.status(), expected_status);
+        assert_eq!(resp.headers().contains_key("X-Error-Id"), expected_error_id);
+    }
+
+    #[tokio::test]
+    async fn test_s3_handle_release_manifest() {
+        let storage = Memory::default();
+        storage.insert("foo.example.com", "index.html", "<h1>v1</h1>", None);
+        storage.insert(
+            "foo.example.com",
+            "releases/2/index.html",
+            "<h1>v2</h1>",
+            None,
+        );
+        storage.insert(
+            "foo.example.com",
+            "release.json",
+            r#"{"index.html": {"key": "releases/2/index.html"}}"#,
+            None,
+        );
+        let site = Site::new(
+            SiteConfig {
+                release_manifest: Some(ReleaseManifestConfig {
+                    object: "release.json".to_string(),
+                    refresh_interval_ms: 30_000,
+                }),
+                ..Default::default()
+            },
+            Arc::new(storage),
+            None,
+        );
+
+        let resp = s3_handle(&site, "foo.example.com", "index.html", None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "<h1>v2</h1>");
+
+        let resp = s3_handle(&site, "bar.example.com", "index.html", None, None, false)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,433 @@
+use crate::config::BasicAuthConfig;
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderMap, AUTHORIZATION};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
+
+/// Upper bound of remembered credentials, which spare a password hash per request.
+const MAX_VERIFIED_CREDENTIALS: usize = 10_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BasicAuthError {
+    #[error("no credentials")]
+    Missing,
+    #[error("malformed credentials")]
+    Malformed,
+    #[error("invalid credentials for user: {0}")]
+    InvalidCredentials(String),
+}
+
+#[derive(Debug, Default)]
+struct Users {
+    hashes: HashMap<String, String>,
+    modified: Option<SystemTime>,
+    /// Digests of credentials that matched `hashes`.
+    verified: HashSet<[u8; 32]>,
+    /// The hash unknown users are checked against, see [`dummy_hash`].
+    dummy_hash: Option<String>,
+}
+
+/// The users of an htpasswd file. The file is read when created and re-read by [`spawn_reload`] when it changes.
+#[derive(Debug)]
+pub struct Htpasswd {
+    config: BasicAuthConfig,
+    challenge: String,
+    users: RwLock<Users>,
+    /// Bounds the password hashes computed at once, as each costs a lot of CPU on purpose.
+    verifications: Semaphore,
+}
+
+impl Htpasswd {
+    pub fn new(config: BasicAuthConfig) -> Self {
+        let realm = config.realm.replace(['\\', '"'], "");
+        let htpasswd = Self {
+            challenge: format!(r#"Basic realm="{}", charset="UTF-8""#, realm),
+            verifications: Semaphore::new(
+                config
+                    .max_concurrent_verifications
+                    .clamp(1, Semaphore::MAX_PERMITS),
+            ),
+            config,
+            users: RwLock::new(Users::default()),
+        };
+        htpasswd.reload();
+        htpasswd
+    }
+
+    /// The `WWW-Authenticate` challenge sent with a 401.
+    pub fn challenge(&self) -> &str {
+        &self.challenge
+    }
+
+    /// Returns the user of the request's `Authorization: Basic` credentials once they match the file.
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<String, BasicAuthError> {
+        let (user, password) = credentials(headers)?;
+        let (hash, dummy_hash) = {
+            let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+            (users.hashes.get(&user).cloned(), users.dummy_hash.clone())
+        };
+        let digest = credentials_digest(&user, &password, hash.as_deref().unwrap_or_default());
+        if hash.is_some() && self.is_verified(&digest) {
+            return Ok(user);
+        }
+
+        let Ok(_permit) = self.verifications.acquire().await else {
+            return Err(BasicAuthError::InvalidCredentials(user));
+        };
+        // Unknown users are checked against a dummy hash so that they take as long as known ones.
+        let verified = tokio::task::spawn_blocking(move || match (hash, dummy_hash) {
+            (Some(hash), _) => verify(&password, &hash),
+            (None, Some(dummy_hash)) => {
+                verify(&password, &dummy_hash);
+                false
+            }
+            (None, None) => false,
+        })
+        .await
+        .unwrap_or_default();
+        if !verified {
+            return Err(BasicAuthError::InvalidCredentials(user));
+        }
+
+        let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
+        if users.verified.len() >= MAX_VERIFIED_CREDENTIALS {
+            users.verified.clear();
+        }
+        users.verified.insert(digest);
+        Ok(user)
+    }
+
+    /// Re-reads the file if it changed since it was last read. A file that fails to read keeps the users.
+    pub fn reload(&self) {
+        let path = &self.config.htpasswd;
+        let modified = match std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                tracing::warn!("failed to stat htpasswd: {}: {}", path.display(), e);
+                return;
+            }
+        };
+        if self
+            .users
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .modified
+            == Some(modified)
+        {
+            return;
+        }
+
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let hashes = parse(&contents);
+                tracing::info!(
+                    "loaded htpasswd: {}: {} users",
+                    path.display(),
+                    hashes.len()
+                );
+                let dummy_hash = dummy_hash(&hashes);
+                *self.users.write().unwrap_or_else(|e| e.into_inner()) = Users {
+                    hashes,
+                    modified: Some(modified),
+                    verified: HashSet::new(),
+                    dummy_hash,
+                };
+            }
+            Err(e) => tracing::warn!("failed to read htpasswd: {}: {}", path.display(), e),
+        }
+    }
+
+    fn is_verified(&self, digest: &[u8; 32]) -> bool {
+        self.users
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .verified
+            .contains(digest)
+    }
+}
+
+/// Checks `htpasswd` for changes every reload interval of the site.
+pub fn spawn_reload(htpasswd: Arc<Htpasswd>) {
+    let period = Duration::from_millis(htpasswd.config.reload_interval_ms.max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let htpasswd = htpasswd.clone();
+            let _ = tokio::task::spawn_blocking(move || htpasswd.reload()).await;
+        }
+    });
+}
+
+fn credentials(headers: &HeaderMap) -> Result<(String, String), BasicAuthError> {
+    let value = headers
+        .get(AUTHORIZATION)
+        .ok_or(BasicAuthError::Missing)?
+        .to_str()
+        .map_err(|_| BasicAuthError::Malformed)?;
+    let encoded = match value.split_once(' ') {
+        Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("Basic") => encoded.trim(),
+        _ => return Err(BasicAuthError::Missing),
+    };
+    let decoded = STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or(BasicAuthError::Malformed)?;
+    let (user, password) = decoded.split_once(':').ok_or(BasicAuthError::Malformed)?;
+
+    Ok((user.to_string(), password.to_string()))
+}
+
+/// Parses `user:hash` lines, skipping comments and hashes other than bcrypt and argon2.
+fn parse(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match line.split_once(':') {
+            Some((user, hash)) if is_supported(hash) => Some((user.to_string(), hash.to_string())),
+            _ => {
+                tracing::warn!(
+                    "skipped htpasswd entry without a bcrypt or argon2 hash: {}",
+                    line.split(':').next().unwrap_or_default()
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+fn is_supported(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$", "$argon2"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+/// Both hash functions compare the computed hash in constant time.
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+    }
+
+    bcrypt::verify(password, hash).unwrap_or_default()
+}
+
+/// A hash of the file with the algorithm and cost most entries use. Verifying a password against it, with the result
+/// ignored, takes as long as checking a known user. `None` for a file without users.
+fn dummy_hash(hashes: &HashMap<String, String>) -> Option<String> {
+    let mut by_params = HashMap::<&str, (usize, &String)>::new();
+    for hash in hashes.values() {
+        let entry = by_params.entry(hash_params(hash)).or_insert((0, hash));
+        entry.0 += 1;
+    }
+    by_params
+        .into_iter()
+        .max_by_key(|(params, (count, _))| (*count, *params))
+        .map(|(_, (_, hash))| hash.clone())
+}
+
+/// The algorithm and cost part of a hash, e.g. `$2b$12$` or `$argon2id$v=19$m=19456,t=2,p=1$`.
+fn hash_params(hash: &str) -> &str {
+    // The `$` after the cost is the third one of bcrypt hashes and the fourth one of argon2 hashes.
+    let nth = if hash.starts_with("$argon2") { 3 } else { 2 };
+    let end = hash
+        .match_indices('$')
+        .nth(nth)
+        .map_or(hash.len(), |(i, _)| i + 1);
+    &hash[..end]
+}
+
+fn credentials_digest(user: &str, password: &str, hash: &str) -> [u8; 32] {
+    let mut digest = Sha256::new();
+    for part in [user, password, hash] {
+        digest.update(part.len().to_be_bytes());
+        digest.update(part);
+    }
+    digest.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::{Algorithm, Params, Version};
+    use std::path::PathBuf;
+    use test_case::test_case;
+
+    /// A file removed when dropped.
+    struct TempFile {
+        path: PathBuf,
+    }
+
+    impl TempFile {
+        fn new(contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("htpasswd-{}", uuid::Uuid::new_v4()));
+            std::fs::write(&path, contents).unwrap();
+            Self { path }
+        }
+
+        /// Replaces the contents with a later modification time, as a quick rewrite may keep it.
+        fn write(&self, contents: &str) {
+            std::fs::write(&self.path, contents).unwrap();
+            let file = std::fs::File::options()
+                .write(true)
+                .open(&self.path)
+                .unwrap();
+            file.set_modified(SystemTime::now() + Duration::from_secs(1))
+                .unwrap();
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn htpasswd(contents: &str) -> (TempFile, Htpasswd) {
+        let file = TempFile::new(contents);
+        let htpasswd = Htpasswd::new(BasicAuthConfig {
+            htpasswd: file.path.clone(),
+            realm: "staging".to_string(),
+            reload_interval_ms: 5_000,
+            max_concurrent_verifications: 4,
+        });
+        (file, htpasswd)
+    }
+
+    fn basic(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!("Basic {}", STANDARD.encode(credentials))
+                .parse()
+                .unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let alice = bcrypt::hash("wonderland", 4).unwrap();
+        let salt = SaltString::from_b64("c2FsdHNhbHQ").unwrap();
+        let bob = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(16, 2, 1, None).unwrap(),
+        )
+        .hash_password(b"password", &salt)
+        .unwrap()
+        .to_string();
+        let (_file, htpasswd) = htpasswd(&format!(
+            "# staging\nalice:{}\nbob:{}\ncarol:$apr1$abc$def\n",
+            alice, bob
+        ));
+
+        assert_eq!(
+            htpasswd
+                .authenticate(&basic("alice:wonderland"))
+                .await
+                .unwrap(),
+            "alice"
+        );
+        assert_eq!(
+            htpasswd
+                .authenticate(&basic("alice:wonderland"))
+                .await
+                .unwrap(),
+            "alice"
+        );
+        assert_eq!(
+            htpasswd.authenticate(&basic("bob:password")).await.unwrap(),
+            "bob"
+        );
+        assert_eq!(
+            htpasswd.challenge(),
+            r#"Basic realm="staging", charset="UTF-8""#
+        );
+    }
+
+    #[test_case("alice:looking-glass"; "wrong password")]
+    #[test_case("mallory:wonderland"; "unknown user")]
+    #[test_case("carol:secret"; "unsupported hash")]
+    #[tokio::test]
+    async fn test_authenticate_invalid(credentials: &str) {
+        let alice = bcrypt::hash("wonderland", 4).unwrap();
+        let (_file, htpasswd) = htpasswd(&format!("alice:{}\ncarol:$apr1$abc$def\n", alice));
+
+        assert!(matches!(
+            htpasswd.authenticate(&basic(credentials)).await,
+            Err(BasicAuthError::InvalidCredentials(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_missing() {
+        let (_file, htpasswd) = htpasswd("");
+        assert!(matches!(
+            htpasswd.authenticate(&HeaderMap::new()).await,
+            Err(BasicAuthError::Missing)
+        ));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Basic !!!".parse().unwrap());
+        assert!(matches!(
+            htpasswd.authenticate(&headers).await,
+            Err(BasicAuthError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_dummy_hash() {
+        let cost_4 = bcrypt::hash("a", 4).unwrap();
+        let cost_5 = bcrypt::hash("b", 5).unwrap();
+        let hashes = HashMap::from([
+            ("alice".to_string(), cost_4.clone()),
+            ("bob".to_string(), cost_5.clone()),
+            ("carol".to_string(), bcrypt::hash("c", 5).unwrap()),
+        ]);
+
+        assert_eq!(hash_params(&cost_4), "$2b$04$");
+        assert_eq!(
+            hash_params("$argon2id$v=19$m=16,t=2,p=1$c2FsdHNhbHQ$aGFzaA"),
+            "$argon2id$v=19$m=16,t=2,p=1$"
+        );
+        assert_eq!(
+            dummy_hash(&hashes).as_deref().map(hash_params),
+            Some("$2b$05$")
+        );
+        assert_eq!(dummy_hash(&HashMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let (file, htpasswd) = htpasswd(&format!(
+            "alice:{}\n",
+            bcrypt::hash("wonderland", 4).unwrap()
+        ));
+        assert!(htpasswd
+            .authenticate(&basic("alice:wonderland"))
+            .await
+            .is_ok());
+
+        file.write(&format!("alice:{}\n", bcrypt::hash("rotated", 4).unwrap()));
+        htpasswd.reload();
+        assert!(htpasswd
+            .authenticate(&basic("alice:wonderland"))
+            .await
+            .is_err());
+        assert!(htpasswd.authenticate(&basic("alice:rotated")).await.is_ok());
+    }
+}
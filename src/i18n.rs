@@ -0,0 +1,231 @@
+use crate::config::{I18nConfig, I18nMode};
+use crate::response;
+use hyper::header::{ACCEPT_LANGUAGE, COOKIE};
+use hyper::Request;
+
+/// How a request is served in the site's locales.
+#[derive(Debug, PartialEq)]
+pub enum Localized<'a> {
+    /// Serves `path` from the objects under the `locale` prefix. `negotiated` when the locale was not in the path.
+    Serve {
+        locale: &'a str,
+        path: String,
+        negotiated: bool,
+    },
+    /// Redirects to the path prefixed with the chosen locale.
+    Redirect(String),
+}
+
+/// Takes the locale from the first path segment, or chooses one from the query, the cookie and `Accept-Language`,
+/// in that order, falling back to the default locale. `path` is the path after rewrites, which redirects never
+/// expose: they prefix the requested path instead.
+pub fn localize<'a, B>(config: &'a I18nConfig, req: &Request<B>, path: &str) -> Localized<'a> {
+    if let Some((locale, rest)) = prefixed(config, path) {
+        return Localized::Serve {
+            locale,
+            path: rest,
+            negotiated: false,
+        };
+    }
+    // A rewrite may drop the locale of the requested path, which still names it.
+    if let Some((locale, _)) = prefixed(config, req.uri().path()) {
+        return Localized::Serve {
+            locale,
+            path: path.to_string(),
+            negotiated: false,
+        };
+    }
+
+    let locale = requested(config, req)
+        .or_else(|| {
+            req.headers()
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| negotiate(&config.locales, value))
+        })
+        .unwrap_or(&config.default_locale);
+
+    match config.mode {
+        I18nMode::Rewrite => Localized::Serve {
+            locale,
+            path: path.to_string(),
+            negotiated: true,
+        },
+        I18nMode::Redirect => {
+            let mut location = format!("/{}{}", locale, req.uri().path());
+            if let Some(query) = req.uri().query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            Localized::Redirect(location)
+        }
+    }
+}
+
+/// Request headers a negotiated response depends on.
+pub fn vary(config: &I18nConfig) -> impl Iterator<Item = &'static str> {
+    let cookie = config.cookie.is_some().then_some("Cookie");
+    std::iter::once("Accept-Language").chain(cookie)
+}
+
+/// The locale of a path such as `/ja/guide/`, and the path without it.
+fn prefixed<'a>(config: &'a I18nConfig, path: &str) -> Option<(&'a str, String)> {
+    let trimmed = path.trim_start_matches('/');
+    let (segment, rest) = trimmed.split_once('/').unwrap_or((trimmed, ""));
+    let locale = find(&config.locales, segment)?;
+    Some((locale, format!("/{}", rest)))
+}
+
+/// The locale named by the query parameter or the cookie.
+fn requested<'a, B>(config: &'a I18nConfig, req: &Request<B>) -> Option<&'a str> {
+    let from_query = config.query.as_ref().and_then(|name| {
+        req.uri()
+            .query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(param, _)| param == name)
+            .and_then(|(_, value)| find(&config.locales, value))
+    });
+    from_query.or_else(|| {
+        let name = config.cookie.as_ref()?;
+        req.headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(|cookie| response::cookie_value(cookie, name))
+            .and_then(|value| find(&config.locales, value))
+    })
+}
+
+/// Picks the locale best matching the language ranges of an `Accept-Language` header. A range matches a locale
+/// of the same tag, a more specific one such as `en-US` for `en`, or a less specific one such as `ja` for `ja-JP`.
+fn negotiate<'a>(locales: &'a [String], accept_language: &str) -> Option<&'a str> {
+    let mut ranges = response::quality_values(accept_language);
+    // A stable sort keeps the header's order among equal qualities.
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    ranges
+        .iter()
+        .filter(|(range, q)| *q > 0.0 && range != "*")
+        .find_map(|(range, _)| {
+            find(locales, range)
+                .or_else(|| {
+                    locales
+                        .iter()
+                        .find(|locale| has_subtag_prefix(locale, range))
+                        .map(String::as_str)
+                })
+                .or_else(|| {
+                    locales
+                        .iter()
+                        .find(|locale| has_subtag_prefix(range, locale))
+                        .map(String::as_str)
+                })
+        })
+}
+
+fn find<'a>(locales: &'a [String], tag: &str) -> Option<&'a str> {
+    locales
+        .iter()
+        .find(|locale| locale.eq_ignore_ascii_case(tag))
+        .map(String::as_str)
+}
+
+/// Whether `tag` starts with the subtags of `prefix`, e.g. `en-US` with `en`.
+fn has_subtag_prefix(tag: &str, prefix: &str) -> bool {
+    tag.as_bytes().get(prefix.len()) == Some(&b'-')
+        && tag
+            .get(..prefix.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn config(mode: I18nMode) -> I18nConfig {
+        I18nConfig {
+            locales: vec!["en".to_string(), "ja".to_string(), "pt-BR".to_string()],
+            default_locale: "en".to_string(),
+            mode,
+            cookie: Some("lang".to_string()),
+            query: Some("lang".to_string()),
+        }
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test_case("ja,en;q=0.8", Some("ja"); "first")]
+    #[test_case("fr;q=1.0, en;q=0.5, ja;q=0.9", Some("ja"); "quality")]
+    #[test_case("ja-JP", Some("ja"); "less specific locale")]
+    #[test_case("PT", Some("pt-BR"); "more specific locale")]
+    #[test_case("ja;q=0, fr", None; "refused")]
+    #[test_case("*", None; "any")]
+    fn test_negotiate(accept_language: &str, expected: Option<&str>) {
+        let locales = config(I18nMode::Rewrite).locales;
+        assert_eq!(negotiate(&locales, accept_language), expected);
+    }
+
+    #[test_case("/guide/", &[("Accept-Language", "ja")], "ja", "/guide/", true; "accept language")]
+    #[test_case("/guide/", &[("Accept-Language", "fr")], "en", "/guide/", true; "default locale")]
+    #[test_case("/guide/?lang=pt-br", &[("Accept-Language", "ja")], "pt-BR", "/guide/", true; "query")]
+    #[test_case("/guide/", &[("Accept-Language", "en"), ("Cookie", "theme=dark; lang=ja")], "ja", "/guide/", true; "cookie")]
+    #[test_case("/ja/guide/", &[("Accept-Language", "en")], "ja", "/guide/", false; "prefixed path")]
+    #[test_case("/ja", &[], "ja", "/", false; "locale root")]
+    #[test_case("/japan/", &[], "en", "/japan/", true; "not a locale segment")]
+    fn test_localize(
+        uri: &str,
+        headers: &[(&str, &str)],
+        locale: &str,
+        path: &str,
+        negotiated: bool,
+    ) {
+        let config = config(I18nMode::Rewrite);
+        let req = request(uri, headers);
+        assert_eq!(
+            localize(&config, &req, req.uri().path()),
+            Localized::Serve {
+                locale,
+                path: path.to_string(),
+                negotiated
+            }
+        );
+    }
+
+    #[test]
+    fn test_localize_redirect() {
+        let config = config(I18nMode::Redirect);
+        let req = request("/guide/?page=2", &[("Accept-Language", "ja")]);
+        assert_eq!(
+            localize(&config, &req, req.uri().path()),
+            Localized::Redirect("/ja/guide/?page=2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_localize_rewritten() {
+        let config = config(I18nMode::Redirect);
+        let req = request("/home?page=2", &[("Accept-Language", "ja")]);
+        assert_eq!(
+            localize(&config, &req, "/index.html"),
+            Localized::Redirect("/ja/home?page=2".to_string())
+        );
+
+        let req = request("/ja/home", &[]);
+        assert_eq!(
+            localize(&config, &req, "/index.html"),
+            Localized::Serve {
+                locale: "ja",
+                path: "/index.html".to_string(),
+                negotiated: false
+            }
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use crate::config::JwtConfig;
+use crate::response;
+use hyper::header::{HeaderMap, AUTHORIZATION, COOKIE};
+use hyper::StatusCode;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Shortest time between JWKS loads, so that tokens naming unknown keys cannot hammer the JWKS source.
+const JWKS_MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Claims = Map<String, Value>;
+
+type HttpsClient =
+    hyper_0_14::Client<hyper_rustls::HttpsConnector<hyper_0_14::client::HttpConnector>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error("no token")]
+    Missing,
+    #[error("invalid token: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+    #[error("unknown key: {0}")]
+    UnknownKey(String),
+    #[error("required claim not satisfied: {0}")]
+    Claim(String),
+    #[error("failed to load jwks: {0}")]
+    Jwks(String),
+}
+
+impl JwtError {
+    /// 401 without a valid token, 403 when a valid token lacks a required claim.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            JwtError::Claim(_) => StatusCode::FORBIDDEN,
+            JwtError::Jwks(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// The `WWW-Authenticate` challenge sent with a 401.
+    pub fn challenge(&self) -> &'static str {
+        match self {
+            JwtError::Missing => "Bearer",
+            _ => r#"Bearer error="invalid_token""#,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LoadedJwks {
+    keys: Arc<JwkSet>,
+    loaded_at: Instant,
+    reload_at: Instant,
+}
+
+/// Verifies the tokens of a site. The JWKS is loaded on first use and reloaded every `jwks_refresh_interval_ms`,
+/// or earlier when a token names a key it does not have, which picks up rotated keys.
+pub struct JwtAuth {
+    config: JwtConfig,
+    jwks: RwLock<Option<LoadedJwks>>,
+    reload: tokio::sync::Mutex<()>,
+    client: HttpsClient,
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtAuth {
+    pub fn new(config: JwtConfig) -> Self {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Self {
+            config,
+            jwks: RwLock::new(None),
+            reload: tokio::sync::Mutex::new(()),
+            client: hyper_0_14::Client::builder().build(https),
+        }
+    }
+
+    /// Returns the claims of the request's token once its signature, issuer, audience, expiry and
+    /// required claims are verified.
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Claims, JwtError> {
+        let token = self.find_token(headers).ok_or(JwtError::Missing)?;
+        let header = jsonwebtoken::decode_header(token)?;
+        let keys = self.jwk_set(header.kid.as_deref()).await?;
+        let jwk = find_key(&keys, header.kid.as_deref())?;
+        if let Some(key_algorithm) = jwk.common.key_algorithm {
+            if key_algorithm.to_string() != format!("{:?}", header.alg) {
+                return Err(JwtError::Invalid(ErrorKind::InvalidAlgorithm.into()));
+            }
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        if self.config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.config.audience);
+        }
+        validation.set_required_spec_claims(&["exp", "iss"]);
+        validation.leeway = self.config.leeway_secs;
+
+        let claims =
+            jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_jwk(jwk)?, &validation)?
+                .claims;
+        for (name, expected) in &self.config.required_claims {
+            if !has_claim(&claims, name, expected) {
+                return Err(JwtError::Claim(name.clone()));
+            }
+        }
+
+        Ok(claims)
+    }
+
+    fn find_token<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        let bearer = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token.trim());
+        let from_cookie = || {
+            let name = self.config.cookie.as_deref()?;
+            headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .find_map(|value| response::cookie_value(value, name))
+        };
+
+        bearer.or_else(from_cookie)
+    }
+
+    async fn jwk_set(&self, kid: Option<&str>) -> Result<Arc<JwkSet>, JwtError> {
+        let loaded_at = match self.loaded(kid) {
+            Ok(keys) => return Ok(keys),
+            Err(loaded_at) => loaded_at,
+        };
+
+        let _reload = self.reload.lock().await;
+        // Another request may have reloaded the keys while this one waited.
+        if let Some(ref jwks) = *self.jwks.read().unwrap_or_else(|e| e.into_inner()) {
+            if Some(jwks.loaded_at) != loaded_at {
+                return Ok(jwks.keys.clone());
+            }
+        }
+
+        let now = Instant::now();
+        let result = self.load().await;
+        let mut jwks = self.jwks.write().unwrap_or_else(|e| e.into_inner());
+        match (result, jwks.take()) {
+            (Ok(keys), _) => {
+                let keys = Arc::new(keys);
+                *jwks = Some(LoadedJwks {
+                    keys: keys.clone(),
+                    loaded_at: now,
+                    reload_at: now + Duration::from_millis(self.config.jwks_refresh_interval_ms),
+                });
+                Ok(keys)
+            }
+            (Err(e), Some(previous)) => {
+                tracing::warn!("failed to reload jwks: {}: {}", self.config.jwks, e);
+                let keys = previous.keys.clone();
+                *jwks = Some(LoadedJwks {
+                    keys: previous.keys,
+                    loaded_at: now,
+                    reload_at: now + JWKS_MIN_RELOAD_INTERVAL,
+                });
+                Ok(keys)
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    /// The loaded keys if they are fresh and, unless a reload is too recent, contain `kid`.
+    /// Otherwise when they were loaded.
+    fn loaded(&self, kid: Option<&str>) -> Result<Arc<JwkSet>, Option<Instant>> {
+        let jwks = self.jwks.read().unwrap_or_else(|e| e.into_inner());
+        let Some(ref jwks) = *jwks else {
+            return Err(None);
+        };
+
+        let now = Instant::now();
+        let unknown_key = kid.is_some_and(|kid| jwks.keys.find(kid).is_none());
+        let may_reload = now.duration_since(jwks.loaded_at) >= JWKS_MIN_RELOAD_INTERVAL;
+        if now >= jwks.reload_at || (unknown_key && may_reload) {
+            return Err(Some(jwks.loaded_at));
+        }
+
+        Ok(jwks.keys.clone())
+    }
+
+    async fn load(&self) -> Result<JwkSet, JwtError> {
+        let source = &self.config.jwks;
+        let body = if source.starts_with("https://") || source.starts_with("http://") {
+            self.fetch(source).await?
+        } else {
+            tokio::fs::read(source)
+                .await
+                .map_err(|e| JwtError::Jwks(e.to_string()))?
+                .into()
+        };
+
+        serde_json::from_slice(&body).map_err(|e| JwtError::Jwks(e.to_string()))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<bytes::Bytes, JwtError> {
+        let uri = url
+            .parse::<hyper_0_14::Uri>()
+            .map_err(|e| JwtError::Jwks(e.to_string()))?;
+        let resp = tokio::time::timeout(JWKS_FETCH_TIMEOUT, self.client.get(uri))
+            .await
+            .map_err(|e| JwtError::Jwks(e.to_string()))?
+            .map_err(|e| JwtError::Jwks(e.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(JwtError::Jwks(format!("status {}", resp.status())));
+        }
+
+        hyper_0_14::body::to_bytes(resp.into_body())
+            .await
+            .map_err(|e| JwtError::Jwks(e.to_string()))
+    }
+}
+
+/// The key named by `kid`, or the only key when the token names none.
+fn find_key<'a>(keys: &'a JwkSet, kid: Option<&str>) -> Result<&'a Jwk, JwtError> {
+    match kid {
+        Some(kid) => keys
+            .find(kid)
+            .ok_or_else(|| JwtError::UnknownKey(kid.to_string())),
+        None => match keys.keys.as_slice() {
+            [key] => Ok(key),
+            _ => Err(JwtError::UnknownKey("no kid".to_string())),
+        },
+    }
+}
+
+fn has_claim(claims: &Claims, name: &str, expected: &str) -> bool {
+    match claims.get(name) {
+        Some(Value::String(value)) => value == expected,
+        Some(Value::Array(values)) => values.iter().any(|value| value.as_str() == Some(expected)),
+        Some(Value::Bool(value)) => value.to_string() == expected,
+        Some(Value::Number(value)) => value.to_string() == expected,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use test_case::test_case;
+
+    const CURRENT_KEY: &str = include_str!("../tests/data/jwt/test-2024-06.pem");
+    const PREVIOUS_KEY: &str = include_str!("../tests/data/jwt/test-2024-01.pem");
+    const ISSUER: &str = "https://login.example.com/";
+
+    fn auth() -> JwtAuth {
+        JwtAuth::new(JwtConfig {
+            issuer: ISSUER.to_string(),
+            audience: vec!["docs".to_string()],
+            jwks: concat!(env!("CARGO_MANIFEST_DIR"), "/tests/config/jwks.json").to_string(),
+            jwks_refresh_interval_ms: 300_000,
+            cookie: Some("session".to_string()),
+            required_claims: HashMap::from([("groups".to_string(), "employees".to_string())]),
+            leeway_secs: 0,
+        })
+    }
+
+    fn token(kid: Option<&str>, pem: &str, claims: Value) -> String {
+        let header = Header {
+            kid: kid.map(str::to_string),
+            ..Header::new(Algorithm::ES256)
+        };
+        jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_ec_pem(pem.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": "docs",
+            "exp": signature::now() + 600,
+            "sub": "alice",
+            "groups": ["staff", "employees"],
+        })
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let auth = auth();
+
+        let current = token(Some("test-2024-06"), CURRENT_KEY, claims());
+        let claims = auth.authenticate(&bearer(&current)).await.unwrap();
+        assert_eq!(claims["sub"], "alice");
+
+        let previous = token(Some("test-2024-01"), PREVIOUS_KEY, self::claims());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            format!("theme=dark; session={}", previous).parse().unwrap(),
+        );
+        assert!(auth.authenticate(&headers).await.is_ok());
+    }
+
+    #[test_case(Some("test-2024-06"), CURRENT_KEY, json!({"exp": signature::now() - 60}), StatusCode::UNAUTHORIZED; "expired token")]
+    #[test_case(Some("test-2024-06"), CURRENT_KEY, json!({"iss": "https://evil.example.com/"}), StatusCode::UNAUTHORIZED; "wrong issuer")]
+    #[test_case(Some("test-2024-06"), CURRENT_KEY, json!({"aud": "billing"}), StatusCode::UNAUTHORIZED; "wrong audience")]
+    #[test_case(Some("test-2024-06"), CURRENT_KEY, json!({"groups": ["contractors"]}), StatusCode::FORBIDDEN; "missing required claim")]
+    #[test_case(Some("test-2024-06"), PREVIOUS_KEY, json!({}), StatusCode::UNAUTHORIZED; "signed with another key")]
+    #[test_case(Some("unknown"), CURRENT_KEY, json!({}), StatusCode::UNAUTHORIZED; "unknown key")]
+    #[test_case(None, CURRENT_KEY, json!({}), StatusCode::UNAUTHORIZED; "no kid with several keys")]
+    #[tokio::test]
+    async fn test_authenticate_rejected(
+        kid: Option<&str>,
+        pem: &str,
+        overrides: Value,
+        expected_status: StatusCode,
+    ) {
+        let mut claims = claims();
+        if let (Value::Object(claims), Value::Object(overrides)) = (&mut claims, overrides) {
+            claims.extend(overrides);
+        }
+        let token = token(kid, pem, claims);
+        let e = auth().authenticate(&bearer(&token)).await.unwrap_err();
+        assert_eq!(e.status(), expected_status, "{}", e);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_missing() {
+        let auth = auth();
+        let e = auth.authenticate(&HeaderMap::new()).await.unwrap_err();
+        assert!(matches!(e, JwtError::Missing));
+        assert_eq!(e.challenge(), "Bearer");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0".parse().unwrap());
+        assert!(matches!(
+            auth.authenticate(&headers).await,
+            Err(JwtError::Missing)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_jwks_unavailable() {
+        let auth = JwtAuth::new(JwtConfig {
+            jwks: "/nonexistent/jwks.json".to_string(),
+            ..auth().config
+        });
+        let token = token(Some("test-2024-06"), CURRENT_KEY, claims());
+        let e = auth.authenticate(&bearer(&token)).await.unwrap_err();
+        assert_eq!(e.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -2,18 +2,51 @@ use futures_util::future::try_join;
 use std::net::SocketAddr;
 use std::process::exit;
 
+mod aws;
+mod bucket_cache;
+mod bucket_owner;
+mod cache_control;
+mod cli;
+mod client_ip;
+mod concurrency;
 mod config;
+mod connection;
+mod cors;
+mod domain;
+mod filesystem;
 mod handler;
+mod htpasswd;
+mod i18n;
+mod jwt;
+mod memory;
+mod metrics;
+mod proxy_protocol;
+mod rate_limit;
+mod release;
 mod response;
+mod rewrite;
 mod router;
 mod s3;
+mod security_headers;
 mod server;
 mod service;
+mod signature;
+mod site;
+mod storage;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(args) {
+            eprintln!("{}\n{}", e, cli::USAGE);
+            exit(2);
+        }
+        return Ok(());
+    }
+
     tracing_subscriber::fmt()
         .json()
         .with_max_level(tracing::Level::INFO)
@@ -24,16 +57,26 @@ async fn main() -> Result<(), Error> {
     let config = config::AppConfig::new();
     tracing::info!("application config: {:?}", config);
 
+    let connection = config.connection();
     let gateway = server::GatewayServer::builder()
         .addr(SocketAddr::from(([0, 0, 0, 0], config.gateway_port)))
+        .default_site(config.default_site())
+        .sites(config.sites())
+        .aws_client(config.aws_client())
+        .bucket_owner_cache(config.bucket_owner_cache())
+        .request_timeout(config.request_timeout())
+        .concurrency(config.concurrency())
+        .connection(connection)
+        .trusted_proxies(config.trusted_proxies.clone())
+        .forwarded_header(config.forwarded_header)
+        .proxy_protocol(config.proxy_protocol)
         .allow_domains(config.allow_domains)
-        .root_object(config.root_object)
-        .subdir_root_object(config.subdir_root_object)
-        .no_such_key_redirect_object(config.no_such_key_redirect_object)
         .allow_cross_account(config.allow_cross_account)
         .build();
     let management = server::ManagementServer::builder()
         .addr(SocketAddr::from(([0, 0, 0, 0], config.management_port)))
+        .proxy_protocol(config.management_proxy_protocol)
+        .connection(connection)
         .build();
 
     if let Err(e) = try_join(gateway, management).await {
@@ -0,0 +1,97 @@
+use crate::storage::{self, GetObjectResult, HeadObjectResult, Storage, StorageError};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone)]
+struct MemoryObject {
+    body: Bytes,
+    website_redirect_location: Option<String>,
+}
+
+/// Keeps objects in memory, keyed by bucket and key.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    objects: Arc<RwLock<HashMap<(String, String), MemoryObject>>>,
+}
+
+impl Memory {
+    pub fn insert(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: impl Into<Bytes>,
+        website_redirect_location: Option<String>,
+    ) {
+        self.objects
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                (bucket.to_string(), key.to_string()),
+                MemoryObject {
+                    body: body.into(),
+                    website_redirect_location,
+                },
+            );
+    }
+
+    fn get(&self, bucket: &str, key: &str) -> Option<MemoryObject> {
+        self.objects
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&(bucket.to_string(), key.to_string()))
+            .cloned()
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for Memory {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        storage::unversioned(version_id)?;
+        let object = self.get(bucket, key).ok_or(StorageError::NoSuchKey)?;
+        Ok(GetObjectResult::builder()
+            .body(object.body)
+            .website_redirect_location(object.website_redirect_location)
+            .build())
+    }
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        storage::unversioned(version_id)?;
+        let object = self.get(bucket, key).ok_or(StorageError::NoSuchKey)?;
+        Ok(HeadObjectResult::builder()
+            .website_redirect_location(object.website_redirect_location)
+            .build())
+    }
+
+    async fn head_bucket(
+        &self,
+        bucket: &str,
+        _expected_bucket_owner: &str,
+    ) -> Result<(), StorageError> {
+        let exists = self
+            .objects
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .any(|(b, _)| b == bucket);
+
+        if exists {
+            Ok(())
+        } else {
+            Err(StorageError::NotFound(format!(
+                "no such bucket: {}",
+                bucket
+            )))
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub static BUCKET_OWNER_CACHE_HITS: Counter = Counter::new(
+    "storage_gateway_bucket_owner_cache_hits_total",
+    "Bucket owner checks answered from the cache.",
+);
+pub static BUCKET_OWNER_CACHE_MISSES: Counter = Counter::new(
+    "storage_gateway_bucket_owner_cache_misses_total",
+    "Bucket owner checks that called HeadBucket.",
+);
+
+pub static RATE_LIMITED_REQUESTS: Counter = Counter::new(
+    "storage_gateway_rate_limited_requests_total",
+    "Requests rejected with 429 by a site's rate limit.",
+);
+
+pub static SHED_REQUESTS: Counter = Counter::new(
+    "storage_gateway_shed_requests_total",
+    "Requests rejected with 503 after waiting too long for a per-host or S3 slot.",
+);
+
+static COUNTERS: [&Counter; 4] = [
+    &BUCKET_OWNER_CACHE_HITS,
+    &BUCKET_OWNER_CACHE_MISSES,
+    &RATE_LIMITED_REQUESTS,
+    &SHED_REQUESTS,
+];
+
+/// A monotonically increasing value exposed in the Prometheus text format.
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut body = String::new();
+    for counter in COUNTERS {
+        let _ = writeln!(body, "# HELP {} {}", counter.name, counter.help);
+        let _ = writeln!(body, "# TYPE {} counter", counter.name);
+        let _ = writeln!(body, "{} {}", counter.name, counter.get());
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let body = render();
+        assert!(body.contains("# TYPE storage_gateway_bucket_owner_cache_hits_total counter\n"));
+        assert!(body.contains("\nstorage_gateway_bucket_owner_cache_misses_total "));
+    }
+}
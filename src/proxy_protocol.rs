@@ -0,0 +1,202 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, `PROXY TCP6` with two full IPv6 addresses and ports, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Source address of a connection as sent by the proxy in front of the gateway, attached to each request.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxiedAddr(pub SocketAddr);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyProtocolError {
+    #[error("failed to read header: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("malformed header: {0}")]
+    Malformed(&'static str),
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of a connection, leaving the rest of the stream unread.
+/// Returns `None` for connections the proxy makes on its own, such as health checks, and for unknown protocols.
+pub async fn read_header<R>(stream: &mut R) -> Result<Option<SocketAddr>, ProxyProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    // Both the v1 prefix and the start of the v2 signature are 6 bytes, shorter than any header.
+    let mut prefix = [0; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY " {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(ProxyProtocolError::Malformed("no proxy protocol signature"))
+    }
+}
+
+async fn read_v1<R>(stream: &mut R) -> Result<Option<SocketAddr>, ProxyProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    line.extend_from_slice(b"PROXY ");
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(ProxyProtocolError::Malformed("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyProtocolError::Malformed("v1 header is not ascii"))?;
+    let fields = line.split(' ').collect::<Vec<&str>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let is_ipv4 = *protocol == "TCP4";
+            let source = parse_v1_address(source, source_port, is_ipv4)?;
+            parse_v1_address(destination, destination_port, is_ipv4)?;
+            Ok(Some(source))
+        }
+        _ => Err(ProxyProtocolError::Malformed("invalid v1 header")),
+    }
+}
+
+fn parse_v1_address(ip: &str, port: &str, is_ipv4: bool) -> Result<SocketAddr, ProxyProtocolError> {
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|_| ProxyProtocolError::Malformed("invalid v1 address"))?;
+    if ip.is_ipv4() != is_ipv4 {
+        return Err(ProxyProtocolError::Malformed("v1 address family mismatch"));
+    }
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| ProxyProtocolError::Malformed("invalid v1 port"))?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+async fn read_v2<R>(stream: &mut R) -> Result<Option<SocketAddr>, ProxyProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; 10];
+    stream.read_exact(&mut header).await?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(ProxyProtocolError::Malformed("invalid v2 signature"));
+    }
+    let (version_command, family) = (header[6], header[7]);
+    let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+    let mut addresses = vec![0; length];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(ProxyProtocolError::Malformed("unsupported v2 version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the proxy's own connection.
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(ProxyProtocolError::Malformed("unsupported v2 command")),
+    }
+
+    let source = match family >> 4 {
+        0x1 => {
+            let addresses: [u8; 12] = addresses
+                .get(..12)
+                .and_then(|addresses| addresses.try_into().ok())
+                .ok_or(ProxyProtocolError::Malformed("v2 ipv4 addresses too short"))?;
+            let ip = Ipv4Addr::from([addresses[0], addresses[1], addresses[2], addresses[3]]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([addresses[8], addresses[9]]))
+        }
+        0x2 => {
+            let addresses: [u8; 36] = addresses
+                .get(..36)
+                .and_then(|addresses| addresses.try_into().ok())
+                .ok_or(ProxyProtocolError::Malformed("v2 ipv6 addresses too short"))?;
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            SocketAddr::new(
+                Ipv6Addr::from(ip).into(),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            )
+        }
+        // UNSPEC or UNIX: no address to report.
+        _ => return Ok(None),
+    };
+
+    Ok(Some(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    async fn read(header: &[u8]) -> (Result<Option<SocketAddr>, ProxyProtocolError>, Vec<u8>) {
+        let mut stream = header;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        header
+    }
+
+    #[test_case(b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 8000\r\nGET / HTTP/1.1\r\n", Some("192.0.2.1:56324"); "v1 tcp4")]
+    #[test_case(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 8000\r\nGET / HTTP/1.1\r\n", Some("[2001:db8::1]:56324"); "v1 tcp6")]
+    #[test_case(b"PROXY UNKNOWN\r\nGET / HTTP/1.1\r\n", None; "v1 unknown")]
+    #[tokio::test]
+    async fn test_read_header_v1(header: &[u8], expected: Option<&str>) {
+        let (result, rest) = read(header).await;
+        assert_eq!(result.unwrap(), expected.map(|addr| addr.parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_header_v2() {
+        let tcp4 = [192, 0, 2, 1, 10, 0, 0, 1, 0xdc, 0x04, 0x1f, 0x40];
+        let (result, rest) = read(&v2(0x1, 0x11, &tcp4)).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let mut tcp6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        tcp6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        tcp6.extend_from_slice(&[0xdc, 0x04, 0x1f, 0x40]);
+        // TLVs after the addresses are skipped.
+        tcp6.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let (result, rest) = read(&v2(0x1, 0x21, &tcp6)).await;
+        assert_eq!(
+            result.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (result, rest) = read(&v2(0x0, 0x00, &[])).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test_case(b"GET / HTTP/1.1\r\nHost: foo.example.com\r\n\r\n".to_vec(); "no header")]
+    #[test_case(b"PROXY TCP4 192.0.2.1 10.0.0.1 56324\r\n".to_vec(); "v1 missing port")]
+    #[test_case(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 8000\r\n".to_vec(); "v1 family mismatch")]
+    #[test_case(b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 80000\r\n".to_vec(); "v1 invalid port")]
+    #[test_case([b"PROXY TCP4 ".as_slice(), &[b'1'; 120], b"\r\n"].concat(); "v1 too long")]
+    #[test_case(b"PROXY TCP4 192.0.2.1".to_vec(); "v1 truncated")]
+    #[test_case(v2(0x1, 0x11, &[192, 0, 2, 1]); "v2 short addresses")]
+    #[test_case(v2(0x2, 0x11, &[0; 12]); "v2 unknown command")]
+    #[test_case(V2_SIGNATURE[..8].to_vec(); "v2 truncated")]
+    #[tokio::test]
+    async fn test_read_header_malformed(header: Vec<u8>) {
+        let (result, _) = read(&header).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::config::{RateLimitConfig, RateLimitKey};
+use crate::metrics;
+use hyper::HeaderMap;
+use lru::LruCache;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token buckets of a site, one per client IP, host or header value. Beyond `max_keys`, the least recently used
+/// bucket is dropped.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<LruCache<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let max_keys = NonZeroUsize::new(config.max_keys).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            config,
+            buckets: Mutex::new(LruCache::new(max_keys)),
+        }
+    }
+
+    /// Takes a token for the request. Fails with the seconds until one is available.
+    /// Requests without the configured header, or with a value not in `header_values`, are keyed by client IP.
+    pub fn check(
+        &self,
+        headers: &HeaderMap,
+        host: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), u64> {
+        let client_ip = || client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let key = match self.config.key {
+            RateLimitKey::Ip => client_ip(),
+            RateLimitKey::Host => host.to_string(),
+            RateLimitKey::Header => self
+                .config
+                .header
+                .as_ref()
+                .and_then(|name| headers.get(name))
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| {
+                    self.config
+                        .header_values
+                        .iter()
+                        .any(|known| known.expose() == *value)
+                })
+                .map(|value| format!("header:{}", value))
+                .unwrap_or_else(client_ip),
+        };
+
+        let result = self.take(key, Instant::now());
+        if result.is_err() {
+            metrics::RATE_LIMITED_REQUESTS.inc();
+        }
+        result
+    }
+
+    fn take(&self, key: String, now: Instant) -> Result<(), u64> {
+        let rate = self.config.requests_per_second;
+        let burst = self.config.burst as f64;
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets.get_or_insert_mut(key, || Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = (1.0 - bucket.tokens) / rate;
+        Err(wait.ceil().max(1.0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Secret;
+    use std::time::Duration;
+    use test_case::test_case;
+
+    fn secret(value: &str) -> Secret {
+        serde_json::from_value(serde_json::Value::from(value)).unwrap()
+    }
+
+    fn limiter(key: RateLimitKey, max_keys: usize) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            key,
+            header: Some("X-Api-Key".to_string()),
+            header_values: vec![secret("a"), secret("b")],
+            requests_per_second: 2.0,
+            burst: 3,
+            max_keys,
+        })
+    }
+
+    #[test]
+    fn test_take() {
+        let limiter = limiter(RateLimitKey::Ip, 10);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.take("192.0.2.1".to_string(), now).is_ok());
+        }
+        assert_eq!(limiter.take("192.0.2.1".to_string(), now), Err(1));
+        assert!(limiter.take("192.0.2.2".to_string(), now).is_ok());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.take("192.0.2.1".to_string(), later).is_ok());
+        assert_eq!(limiter.take("192.0.2.1".to_string(), later), Err(1));
+    }
+
+    #[test]
+    fn test_take_bounded_keys() {
+        let limiter = limiter(RateLimitKey::Ip, 2);
+        let now = Instant::now();
+        for (i, ip) in ["192.0.2.1", "192.0.2.2", "192.0.2.3"].iter().enumerate() {
+            let at = now + Duration::from_millis(i as u64);
+            assert!(limiter.take(ip.to_string(), at).is_ok());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(!buckets.contains("192.0.2.1"));
+    }
+
+    #[test]
+    fn test_check_unknown_header_value() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            header_values: vec![secret("partner")],
+            ..limiter(RateLimitKey::Header, 10).config
+        });
+        let client_ip = "192.0.2.1".parse().ok();
+        for i in 0..3 {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Api-Key", i.to_string().parse().unwrap());
+            assert!(limiter
+                .check(&headers, "foo.example.com", client_ip)
+                .is_ok());
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Api-Key", "rotated".parse().unwrap());
+        assert!(limiter
+            .check(&headers, "foo.example.com", client_ip)
+            .is_err());
+        headers.insert("X-Api-Key", "partner".parse().unwrap());
+        assert!(limiter
+            .check(&headers, "foo.example.com", client_ip)
+            .is_ok());
+    }
+
+    #[test_case(RateLimitKey::Ip, &[], "foo.example.com", "192.0.2.2", true; "ip")]
+    #[test_case(RateLimitKey::Host, &[], "bar.example.com", "192.0.2.1", true; "host")]
+    #[test_case(RateLimitKey::Host, &[], "foo.example.com", "192.0.2.2", false; "same host")]
+    #[test_case(RateLimitKey::Header, &[("X-Api-Key", "b")], "foo.example.com", "192.0.2.1", true; "header")]
+    #[test_case(RateLimitKey::Header, &[("X-Api-Key", "a")], "foo.example.com", "192.0.2.2", false; "same header")]
+    #[test_case(RateLimitKey::Header, &[], "foo.example.com", "192.0.2.2", true; "no header")]
+    fn test_check_key(
+        key: RateLimitKey,
+        headers: &[(&'static str, &str)],
+        host: &str,
+        client_ip: &str,
+        expected_allowed: bool,
+    ) {
+        let limiter = limiter(key, 10);
+        let mut first_headers = HeaderMap::new();
+        first_headers.insert("X-Api-Key", "a".parse().unwrap());
+        let first_ip = "192.0.2.1".parse().ok();
+        for _ in 0..3 {
+            assert!(limiter
+                .check(&first_headers, "foo.example.com", first_ip)
+                .is_ok());
+        }
+
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.insert(*name, value.parse().unwrap());
+        }
+        assert_eq!(
+            limiter
+                .check(&header_map, host, client_ip.parse().ok())
+                .is_ok(),
+            expected_allowed
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use crate::bucket_cache::BucketCache;
+use crate::config::{CorsRule, ReleaseManifestConfig};
+use crate::site::Site;
+use crate::storage::{ExpectedOwner, GetObjectResult, HeadObjectResult, Storage, StorageError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a requested key is read from in a release.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ManifestEntry {
+    /// Key the requested key maps to, e.g. a content addressed one. Defaults to the requested key.
+    pub key: Option<String>,
+    pub version_id: Option<String>,
+}
+
+/// Requested key to the object served for it. Keys not listed are served as they are, and a bucket without
+/// a manifest object has an empty one.
+pub type Manifest = HashMap<String, ManifestEntry>;
+
+/// The release manifests of a site's buckets, read on first use and refreshed by [`spawn_refresh`].
+#[derive(Debug)]
+pub struct ReleaseManifests {
+    config: ReleaseManifestConfig,
+    manifests: BucketCache<Arc<Manifest>>,
+}
+
+impl ReleaseManifests {
+    pub fn new(config: ReleaseManifestConfig) -> Self {
+        Self {
+            config,
+            manifests: BucketCache::new(),
+        }
+    }
+
+    pub async fn manifest<T>(
+        &self,
+        storage: &T,
+        bucket: &str,
+    ) -> Result<Arc<Manifest>, StorageError>
+    where
+        T: Storage + Send + Sync + ?Sized,
+    {
+        if let Some(manifest) = self.manifests.get(bucket) {
+            return Ok(manifest);
+        }
+
+        let manifest = Arc::new(self.load(storage, bucket).await?);
+        self.manifests.insert(bucket, manifest.clone());
+        Ok(manifest)
+    }
+
+    /// Re-reads the manifests of the buckets read so far through the site's bucket owner check.
+    /// A manifest that fails to load keeps its contents, while one of a bucket failing the check is dropped.
+    pub async fn refresh(&self, site: &Site) {
+        for bucket in self.manifests.buckets() {
+            let owner = match site.expected_owner(&bucket).await {
+                Ok(owner) => owner,
+                Err(e) => {
+                    tracing::warn!("dropped release manifest: {}: {}", bucket, e);
+                    self.manifests.remove(&bucket);
+                    continue;
+                }
+            };
+            let storage = ExpectedOwner::new(site.storage.as_ref(), owner.as_deref());
+            match self.load(&storage, &bucket).await {
+                Ok(manifest) => self.manifests.insert(&bucket, Arc::new(manifest)),
+                Err(e) => tracing::warn!(
+                    "failed to refresh release manifest: {}/{}: {}",
+                    bucket,
+                    self.config.object,
+                    e
+                ),
+            }
+        }
+    }
+
+    async fn load<T>(&self, storage: &T, bucket: &str) -> Result<Manifest, StorageError>
+    where
+        T: Storage + Send + Sync + ?Sized,
+    {
+        let object = match storage.get_object(bucket, &self.config.object, None).await {
+            Ok(object) => object,
+            Err(StorageError::NoSuchKey) => {
+                tracing::info!("no release manifest: {}/{}", bucket, self.config.object);
+                return Ok(Manifest::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        serde_json::from_slice(&object.body()).map_err(|e| {
+            StorageError::Internal(format!(
+                "invalid release manifest: {}/{}: {}",
+                bucket, self.config.object, e
+            ))
+        })
+    }
+}
+
+/// Refreshes the site's release manifests every refresh interval. A zero interval turns the refresh off.
+pub fn spawn_refresh(site: Site) {
+    let Some(manifests) = site.release_manifests.clone() else {
+        return;
+    };
+    if manifests.config.refresh_interval_ms == 0 {
+        return;
+    }
+    let period = Duration::from_millis(manifests.config.refresh_interval_ms);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            manifests.refresh(&site).await;
+        }
+    });
+}
+
+/// A storage that reads keys listed in a release manifest from the key and version the manifest names.
+#[derive(Debug)]
+pub struct Released<'a, T: ?Sized> {
+    storage: &'a T,
+    manifest: Option<&'a Manifest>,
+}
+
+impl<'a, T: ?Sized> Released<'a, T> {
+    pub fn new(storage: &'a T, manifest: Option<&'a Manifest>) -> Self {
+        Self { storage, manifest }
+    }
+
+    /// The key and version to read for `key`. An explicit version bypasses the manifest.
+    fn resolve<'b>(
+        &'b self,
+        key: &'b str,
+        version_id: Option<&'b str>,
+    ) -> (&'b str, Option<&'b str>)
+    where
+        'a: 'b,
+    {
+        if version_id.is_some() {
+            return (key, version_id);
+        }
+        match self.manifest.and_then(|manifest| manifest.get(key)) {
+            Some(entry) => (
+                entry.key.as_deref().unwrap_or(key),
+                entry.version_id.as_deref(),
+            ),
+            None => (key, None),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> Storage for Released<'_, T>
+where
+    T: Storage + Send + Sync + ?Sized,
+{
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        let (key, version_id) = self.resolve(key, version_id);
+        self.storage.get_object(bucket, key, version_id).await
+    }
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        let (key, version_id) = self.resolve(key, version_id);
+        self.storage.head_object(bucket, key, version_id).await
+    }
+
+    async fn head_bucket(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: &str,
+    ) -> Result<(), StorageError> {
+        self.storage
+            .head_bucket(bucket, expected_bucket_owner)
+            .await
+    }
+
+    async fn get_bucket_cors(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: Option<&str>,
+    ) -> Result<Vec<CorsRule>, StorageError> {
+        self.storage
+            .get_bucket_cors(bucket, expected_bucket_owner)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket_owner::{BucketOwnerCheck, OwnedStorage};
+    use crate::config::SiteConfig;
+    use crate::memory::Memory;
+    use crate::storage::SharedStorage;
+
+    const MANIFEST: &str = r#"{
+        "index.html": {"key": "releases/2/index.html"},
+        "app.js": {"version_id": "v2"}
+    }"#;
+
+    fn site(storage: SharedStorage, bucket_owner: Option<BucketOwnerCheck>) -> Site {
+        let config = SiteConfig {
+            release_manifest: Some(ReleaseManifestConfig {
+                object: "release.json".to_string(),
+                refresh_interval_ms: 30_000,
+            }),
+            ..Default::default()
+        };
+        Site::new(config, storage, bucket_owner)
+    }
+
+    #[test]
+    fn test_resolve() {
+        let manifest: Manifest = serde_json::from_str(MANIFEST).unwrap();
+        let storage = Memory::default();
+        let released = Released::new(&storage, Some(&manifest));
+
+        assert_eq!(
+            released.resolve("index.html", None),
+            ("releases/2/index.html", None)
+        );
+        assert_eq!(released.resolve("app.js", None), ("app.js", Some("v2")));
+        assert_eq!(released.resolve("style.css", None), ("style.css", None));
+        assert_eq!(
+            released.resolve("index.html", Some("v1")),
+            ("index.html", Some("v1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manifest_refresh() {
+        let storage = Memory::default();
+        storage.insert("foo.example.com", "release.json", MANIFEST, None);
+        let site = site(Arc::new(storage.clone()), None);
+        let manifests = site.release_manifests.clone().unwrap();
+
+        let manifest = manifests
+            .manifest(&storage, "foo.example.com")
+            .await
+            .unwrap();
+        assert_eq!(manifest.len(), 2);
+
+        storage.insert("foo.example.com", "release.json", "{}", None);
+        let manifest = manifests
+            .manifest(&storage, "foo.example.com")
+            .await
+            .unwrap();
+        assert_eq!(manifest.len(), 2);
+
+        manifests.refresh(&site).await;
+        let manifest = manifests
+            .manifest(&storage, "foo.example.com")
+            .await
+            .unwrap();
+        assert!(manifest.is_empty());
+
+        storage.insert("foo.example.com", "release.json", "not json", None);
+        manifests.refresh(&site).await;
+        let manifest = manifests
+            .manifest(&storage, "foo.example.com")
+            .await
+            .unwrap();
+        assert!(manifest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_missing() {
+        let storage = Memory::default();
+        storage.insert("foo.example.com", "index.html", "<h1>index</h1>", None);
+        let site = site(Arc::new(storage.clone()), None);
+        let manifests = site.release_manifests.clone().unwrap();
+
+        let manifest = manifests
+            .manifest(&storage, "foo.example.com")
+            .await
+            .unwrap();
+        assert!(manifest.is_empty());
+
+        storage.insert("foo.example.com", "release.json", MANIFEST, None);
+        manifests.refresh(&site).await;
+        let manifest = manifests
+            .manifest(&storage, "foo.example.com")
+            .await
+            .unwrap();
+        assert_eq!(manifest.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_refresh_bucket_owner_changed() {
+        let storage = Arc::new(OwnedStorage::new("012345678901"));
+        storage
+            .memory
+            .insert("foo.example.com", "release.json", MANIFEST, None);
+        let bucket_owner =
+            BucketOwnerCheck::new(vec!["012345678901".to_string()], Default::default());
+        let site = site(storage.clone(), Some(bucket_owner));
+        let manifests = site.release_manifests.clone().unwrap();
+        manifests
+            .manifest(storage.as_ref(), "foo.example.com")
+            .await
+            .unwrap();
+
+        *storage.owner.write().unwrap() = "123456789012".to_string();
+        manifests.refresh(&site).await;
+        assert!(manifests.manifests.buckets().is_empty());
+    }
+}
@@ -1,14 +1,23 @@
-use crate::s3::S3;
+use crate::cache_control;
+use crate::config::{SiteConfig, SpaFallbackConfig};
+use crate::storage::{GetObjectResult, Storage};
 use bytes::Bytes;
 use http_body_util::Full;
+use hyper::header::{HeaderMap, HeaderValue, RETRY_AFTER, VARY, WWW_AUTHENTICATE};
 use hyper::{Response, StatusCode};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ResponseError {
     #[error("failed to build response: {0}")]
     ResponseBuild(#[from] hyper::http::Error),
+    #[error("invalid header value: {0}")]
+    HeaderValue(#[from] hyper::header::InvalidHeaderValue),
 }
 
+/// Marks a response whose `Content-Type` was guessed from the key rather than known.
+#[derive(Debug, Clone, Copy)]
+pub struct GuessedContentType;
+
 pub fn easy_response(status_code: StatusCode) -> Result<Response<Full<Bytes>>, ResponseError> {
     let body = Full::new(Bytes::from(
         status_code.canonical_reason().unwrap_or_default(),
@@ -20,46 +29,372 @@ pub fn easy_response(status_code: StatusCode) -> Result<Response<Full<Bytes>>, R
         .body(body)?)
 }
 
+pub fn metrics_response(body: String) -> Result<Response<Full<Bytes>>, ResponseError> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Full::new(Bytes::from(body)))?)
+}
+
 pub fn s3_ok_response(
+    site: &SiteConfig,
+    key: &str,
     content_type: String,
-    body: Bytes,
+    object: GetObjectResult,
 ) -> Result<Response<Full<Bytes>>, ResponseError> {
-    Ok(Response::builder()
+    let mut headers = HeaderMap::new();
+    cache_control::apply(&site.cache_control, key, &object, &mut headers);
+    let mut resp = Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", content_type)
-        .body(Full::new(body))?)
+        .extension(GuessedContentType)
+        .body(Full::new(object.body()))?;
+    resp.headers_mut().extend(headers);
+    Ok(resp)
+}
+
+pub fn redirect_response(
+    status_code: StatusCode,
+    location: &str,
+) -> Result<Response<Full<Bytes>>, ResponseError> {
+    Ok(Response::builder()
+        .status(status_code)
+        .header("Content-Type", mime::TEXT_PLAIN.to_string())
+        .header("Location", location)
+        .body(Full::new(Bytes::from(status_code.as_str().to_string())))?)
 }
 
-pub async fn s3_error_response<T>(
-    s3_client: &T,
+pub fn error_response(
+    status_code: StatusCode,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, ResponseError> {
+    let reason = status_code.canonical_reason().unwrap_or_default();
+    let (content_type, body) = match preferred_media_type(accept) {
+        ErrorFormat::Html => (
+            mime::TEXT_HTML_UTF_8.to_string(),
+            format!(
+                "<!DOCTYPE html>\n<html>\n<head><title>{0} {1}</title></head>\n<body><h1>{0} {1}</h1></body>\n</html>\n",
+                status_code.as_u16(),
+                reason,
+            ),
+        ),
+        ErrorFormat::Json => (
+            mime::APPLICATION_JSON.to_string(),
+            format!(
+                r#"{{"status":{},"message":"{}"}}"#,
+                status_code.as_u16(),
+                reason,
+            ),
+        ),
+        ErrorFormat::Text => (mime::TEXT_PLAIN.to_string(), reason.to_string()),
+    };
+
+    Ok(Response::builder()
+        .status(status_code)
+        .header("Content-Type", content_type)
+        .body(Full::new(Bytes::from(body)))?)
+}
+
+pub async fn error_document_response<T>(
+    storage: &T,
     bucket: &str,
-    is_no_such_key: bool,
-    no_such_key_redirect_object: Option<String>,
+    site: &SiteConfig,
+    status_code: StatusCode,
+    accept: Option<&str>,
 ) -> Result<Response<Full<Bytes>>, ResponseError>
 where
-    T: S3 + Send + Sync + 'static,
+    T: Storage + Send + Sync + ?Sized,
 {
-    if is_no_such_key {
-        match no_such_key_redirect_object {
-            Some(redirect_object) => match s3_client.head_object(bucket, &redirect_object).await {
-                Ok(_) => Ok(Response::builder()
-                    .status(StatusCode::FOUND)
-                    .header("Content-Type", mime::TEXT_PLAIN.to_string())
-                    .header("Location", format!("/{}", redirect_object))
-                    .body(Full::new(Bytes::from(StatusCode::FOUND.as_str())))?),
-                Err(e) => {
-                    tracing::warn!(
-                        "no such redirect object: s3://{}/{}: {:?}",
-                        bucket,
-                        redirect_object,
-                        e.into_service_error(),
-                    );
-                    easy_response(StatusCode::NOT_FOUND)
-                }
-            },
-            None => easy_response(StatusCode::NOT_FOUND),
+    let Some(key) = site.error_documents.get(&status_code.as_u16()) else {
+        return error_response(status_code, accept);
+    };
+
+    match storage.get_object(bucket, key, None).await {
+        Ok(resp) => Ok(Response::builder()
+            .status(status_code)
+            .header(
+                "Content-Type",
+                mime_guess::from_path(key)
+                    .first_or(mime::TEXT_HTML)
+                    .to_string(),
+            )
+            .extension(GuessedContentType)
+            .body(Full::new(resp.body()))?),
+        Err(e) => {
+            tracing::warn!("failed to get error document: {}/{}: {}", bucket, key, e);
+            error_response(status_code, accept)
+        }
+    }
+}
+
+/// A 401 asking for credentials with the `WWW-Authenticate` challenge.
+pub fn unauthorized_response(
+    challenge: &str,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, ResponseError> {
+    let mut resp = error_response(StatusCode::UNAUTHORIZED, accept)?;
+    resp.headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_str(challenge)?);
+    Ok(resp)
+}
+
+/// An error response asking the client to retry after `retry_after` seconds, e.g. a 429 or a 503.
+pub fn retry_after_response(
+    status: StatusCode,
+    retry_after: u64,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, ResponseError> {
+    let mut resp = error_response(status, accept)?;
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    Ok(resp)
+}
+
+/// A response with a body given by the site's config rather than read from the storage.
+pub fn fixed_response(
+    status_code: StatusCode,
+    content_type: &str,
+    body: String,
+) -> Result<Response<Full<Bytes>>, ResponseError> {
+    Ok(Response::builder()
+        .status(status_code)
+        .header("Content-Type", content_type)
+        .body(Full::new(Bytes::from(body)))?)
+}
+
+/// An empty answer to an allowed CORS preflight.
+pub fn preflight_response(headers: HeaderMap) -> Result<Response<Full<Bytes>>, ResponseError> {
+    let mut resp = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Full::new(Bytes::new()))?;
+    resp.headers_mut().extend(headers);
+    Ok(resp)
+}
+
+/// Adds `name` to the `Vary` header unless it is already listed.
+pub fn append_vary(headers: &mut HeaderMap, name: &str) {
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|listed| {
+            let listed = listed.trim();
+            listed == "*" || listed.eq_ignore_ascii_case(name)
+        });
+    if !listed {
+        if let Ok(value) = HeaderValue::from_str(name) {
+            headers.append(VARY, value);
         }
-    } else {
-        easy_response(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+pub fn set_error_headers(
+    resp: &mut Response<Full<Bytes>>,
+    error_id: &str,
+    retry_after: Option<u64>,
+) {
+    if let Ok(value) = HeaderValue::from_str(error_id) {
+        resp.headers_mut().insert("X-Error-Id", value);
+    }
+    if let Some(secs) = retry_after {
+        resp.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(secs));
+    }
+}
+
+pub async fn no_such_key_response<T>(
+    storage: &T,
+    bucket: &str,
+    site: &SiteConfig,
+    accept: Option<&str>,
+    is_navigation: bool,
+) -> Result<Response<Full<Bytes>>, ResponseError>
+where
+    T: Storage + Send + Sync + ?Sized,
+{
+    if let Some(ref spa_fallback) = site.spa_fallback {
+        if is_navigation {
+            return spa_fallback_response(storage, bucket, site, spa_fallback, accept).await;
+        }
+        return error_document_response(storage, bucket, site, StatusCode::NOT_FOUND, accept).await;
+    }
+
+    let Some(ref redirect_object) = site.no_such_key_redirect_object else {
+        return error_document_response(storage, bucket, site, StatusCode::NOT_FOUND, accept).await;
+    };
+
+    match storage.head_object(bucket, redirect_object, None).await {
+        Ok(head) => match head.website_redirect_location() {
+            Some(location) => redirect_response(
+                StatusCode::MOVED_PERMANENTLY,
+                &website_redirect_target(redirect_object, location),
+            ),
+            None => redirect_response(StatusCode::FOUND, &format!("/{}", redirect_object)),
+        },
+        Err(e) => {
+            tracing::warn!(
+                "no such redirect object: {}/{}: {}",
+                bucket,
+                redirect_object,
+                e,
+            );
+            error_document_response(storage, bucket, site, StatusCode::NOT_FOUND, accept).await
+        }
+    }
+}
+
+pub async fn spa_fallback_response<T>(
+    storage: &T,
+    bucket: &str,
+    site: &SiteConfig,
+    spa_fallback: &SpaFallbackConfig,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, ResponseError>
+where
+    T: Storage + Send + Sync + ?Sized,
+{
+    match storage.get_object(bucket, &spa_fallback.object, None).await {
+        Ok(object) => {
+            let mut headers = HeaderMap::new();
+            cache_control::apply(
+                &site.cache_control,
+                &spa_fallback.object,
+                &object,
+                &mut headers,
+            );
+            let mut resp = Response::builder()
+                .status(spa_fallback.status.0)
+                .header(
+                    "Content-Type",
+                    mime_guess::from_path(&spa_fallback.object)
+                        .first_or(mime::TEXT_HTML)
+                        .to_string(),
+                )
+                .extension(GuessedContentType)
+                .body(Full::new(object.body()))?;
+            resp.headers_mut().extend(headers);
+            Ok(resp)
+        }
+        Err(e) => {
+            tracing::warn!(
+                "no such spa fallback object: {}/{}: {}",
+                bucket,
+                spa_fallback.object,
+                e,
+            );
+            error_document_response(storage, bucket, site, StatusCode::NOT_FOUND, accept).await
+        }
+    }
+}
+
+/// Splits a header such as `Accept` into its values and `q` parameters.
+pub fn quality_values(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|value| {
+            let mut params = value.split(';');
+            let value = params.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((value, quality))
+        })
+        .collect()
+}
+
+/// Finds the value of the cookie `name` in a `Cookie` header.
+pub fn cookie_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorFormat {
+    Text,
+    Html,
+    Json,
+}
+
+/// Picks the error body format with the highest quality in the `Accept` header.
+/// Ties are resolved in the order text, HTML, JSON so that `*/*` keeps plain text bodies.
+fn preferred_media_type(accept: Option<&str>) -> ErrorFormat {
+    let Some(accept) = accept else {
+        return ErrorFormat::Text;
+    };
+
+    let ranges = quality_values(accept);
+    let quality_of = |media_type: &str| {
+        let (top, _) = media_type.split_once('/').unwrap_or_default();
+        ranges
+            .iter()
+            .filter(|(range, _)| {
+                range == media_type || range == "*/*" || *range == format!("{}/*", top)
+            })
+            .map(|(_, q)| *q)
+            .fold(0.0, f32::max)
+    };
+
+    [
+        (ErrorFormat::Text, mime::TEXT_PLAIN.as_ref()),
+        (ErrorFormat::Html, mime::TEXT_HTML.as_ref()),
+        (ErrorFormat::Json, mime::APPLICATION_JSON.as_ref()),
+    ]
+    .into_iter()
+    .map(|(format, media_type)| (format, quality_of(media_type)))
+    .filter(|(_, q)| *q > 0.0)
+    .fold(
+        None,
+        |best: Option<(ErrorFormat, f32)>, (format, q)| match best {
+            Some((_, best_q)) if best_q >= q => best,
+            _ => Some((format, q)),
+        },
+    )
+    .map(|(format, _)| format)
+    .unwrap_or(ErrorFormat::Text)
+}
+
+pub fn website_redirect_target(key: &str, location: &str) -> String {
+    if location.starts_with('/')
+        || location.starts_with("http://")
+        || location.starts_with("https://")
+    {
+        return location.to_string();
+    }
+
+    match key.rsplit_once('/') {
+        Some((dir, _)) => format!("/{}/{}", dir, location),
+        None => format!("/{}", location),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("old.html", "/new.html", "/new.html"; "root relative")]
+    #[test_case("blog/old.html", "https://example.com/new", "https://example.com/new"; "absolute url")]
+    #[test_case("blog/old.html", "new.html", "/blog/new.html"; "relative to key")]
+    #[test_case("old.html", "new.html", "/new.html"; "relative to root key")]
+    fn test_website_redirect_target(key: &str, location: &str, expected: &str) {
+        assert_eq!(website_redirect_target(key, location), expected);
+    }
+
+    #[test_case(None, ErrorFormat::Text; "no accept header")]
+    #[test_case(Some("*/*"), ErrorFormat::Text; "any")]
+    #[test_case(Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"), ErrorFormat::Html; "browser")]
+    #[test_case(Some("application/json"), ErrorFormat::Json; "json")]
+    #[test_case(Some("text/html;q=0.5, application/json"), ErrorFormat::Json; "json preferred by quality")]
+    #[test_case(Some("image/png"), ErrorFormat::Text; "no acceptable format")]
+    fn test_preferred_media_type(accept: Option<&str>, expected: ErrorFormat) {
+        assert_eq!(preferred_media_type(accept), expected);
     }
 }
@@ -0,0 +1,345 @@
+use crate::config::{RewriteAction, RewriteRule};
+use crate::response;
+use hyper::header::{HeaderMap, HeaderValue, VARY};
+use hyper::{Request, StatusCode};
+use regex::Captures;
+
+/// What the rewrite rules of a site decided for a request.
+#[derive(Debug)]
+pub struct Rewritten {
+    /// The path the key is resolved from.
+    pub path: String,
+    /// Headers added to the response by [`add_headers`].
+    pub headers: HeaderMap,
+    /// A response served instead of an object.
+    pub outcome: Option<Outcome>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Redirect {
+        status: StatusCode,
+        location: String,
+    },
+    Respond {
+        status: StatusCode,
+        content_type: String,
+        body: String,
+    },
+}
+
+/// Applies the rules matching the request in order, until one redirects or responds.
+pub fn evaluate<B>(rules: &[RewriteRule], req: &Request<B>, host: &str) -> Rewritten {
+    let mut rewritten = Rewritten {
+        path: req.uri().path().to_string(),
+        headers: HeaderMap::new(),
+        outcome: None,
+    };
+
+    for rule in rules {
+        let current = rewritten.path.clone();
+        let Some(captures) = matches(rule, req, host, &current) else {
+            continue;
+        };
+        let expand = |template: &str| match captures {
+            Some(ref captures) => {
+                let mut expanded = String::new();
+                captures.expand(template, &mut expanded);
+                expanded
+            }
+            None => template.to_string(),
+        };
+
+        match rule.action {
+            RewriteAction::Rewrite { ref path } => {
+                let path = expand(path);
+                tracing::info!("rewrite: {} -> {}", rewritten.path, path);
+                rewritten.path = match path.starts_with('/') {
+                    true => path,
+                    false => format!("/{}", path),
+                };
+            }
+            RewriteAction::Redirect {
+                ref location,
+                status,
+            } => {
+                let mut location = expand(location);
+                if let Some(query) = req.uri().query() {
+                    if !location.contains('?') {
+                        location.push('?');
+                        location.push_str(query);
+                    }
+                }
+                rewritten.outcome = Some(Outcome::Redirect {
+                    status: status.0,
+                    location,
+                });
+                break;
+            }
+            RewriteAction::Headers { ref headers } => {
+                for (name, value) in headers {
+                    let value = expand(value);
+                    match HeaderValue::from_str(&value) {
+                        Ok(value) if name.0 == VARY => {
+                            rewritten.headers.append(VARY, value);
+                        }
+                        Ok(value) => {
+                            rewritten.headers.insert(name.0.clone(), value);
+                        }
+                        Err(_) => tracing::warn!("invalid rewrite header: {}: {}", name.0, value),
+                    }
+                }
+            }
+            RewriteAction::Respond {
+                status,
+                ref body,
+                ref content_type,
+            } => {
+                rewritten.outcome = Some(Outcome::Respond {
+                    status: status.0,
+                    content_type: content_type
+                        .clone()
+                        .unwrap_or_else(|| mime::TEXT_PLAIN_UTF_8.to_string()),
+                    body: expand(body),
+                });
+                break;
+            }
+        }
+    }
+
+    rewritten
+}
+
+/// Adds the headers of header rules to a response. `Vary` names join the response's, and other headers are only
+/// added when the response does not carry them, so rules never undo what serving the object set.
+pub fn add_headers(headers: HeaderMap, resp_headers: &mut HeaderMap) {
+    for (name, value) in headers.iter() {
+        if name == VARY {
+            for listed in value.to_str().unwrap_or_default().split(',') {
+                if !listed.trim().is_empty() {
+                    response::append_vary(resp_headers, listed.trim());
+                }
+            }
+        } else if !resp_headers.contains_key(name) {
+            resp_headers.insert(name, value.clone());
+        }
+    }
+}
+
+/// Whether every condition of the rule holds. `Some(None)` when it matches without a path pattern.
+fn matches<'p, B>(
+    rule: &RewriteRule,
+    req: &Request<B>,
+    host: &str,
+    path: &'p str,
+) -> Option<Option<Captures<'p>>> {
+    if rule
+        .host
+        .as_ref()
+        .is_some_and(|host_pattern| !host_pattern.0.is_match(host))
+    {
+        return None;
+    }
+    if !rule.methods.is_empty()
+        && !rule
+            .methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(req.method().as_str()))
+    {
+        return None;
+    }
+    let headers_match = rule.headers.iter().all(|(name, pattern)| {
+        req.headers()
+            .get_all(name.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| pattern.0.is_match(value))
+    });
+    if !headers_match {
+        return None;
+    }
+    let query_match = rule.query.iter().all(|(name, pattern)| {
+        req.uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .any(|(param, value)| param == name && pattern.0.is_match(value))
+    });
+    if !query_match {
+        return None;
+    }
+
+    match rule.path {
+        Some(ref path_pattern) => path_pattern.0.captures(path).map(Some),
+        None => Some(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Pattern, RedirectStatus, RespondStatus, ResponseHeaderName};
+    use hyper::header::HeaderName;
+    use std::collections::HashMap;
+    use test_case::test_case;
+
+    fn rule(path: Option<&str>, action: RewriteAction) -> RewriteRule {
+        RewriteRule {
+            path: path.map(|path| Pattern::try_from(path.to_string()).unwrap()),
+            host: None,
+            methods: Vec::new(),
+            headers: HashMap::new(),
+            query: HashMap::new(),
+            action,
+        }
+    }
+
+    fn rules() -> Vec<RewriteRule> {
+        vec![
+            rule(
+                Some(r"^/blog/(?<slug>[^/]+)$"),
+                RewriteAction::Rewrite {
+                    path: "/blog/posts/${slug}.html".to_string(),
+                },
+            ),
+            RewriteRule {
+                headers: HashMap::from([(
+                    "Accept-Language".to_string(),
+                    Pattern::try_from("^fr".to_string()).unwrap(),
+                )]),
+                ..rule(
+                    Some(r"^/docs/(.*)$"),
+                    RewriteAction::Rewrite {
+                        path: "fr/docs/$1".to_string(),
+                    },
+                )
+            },
+            rule(
+                Some(r"^(?<page>/.+)\.html$"),
+                RewriteAction::Headers {
+                    headers: HashMap::from([(
+                        ResponseHeaderName(HeaderName::from_static("x-page")),
+                        "${page}".to_string(),
+                    )]),
+                },
+            ),
+            RewriteRule {
+                methods: vec!["GET".to_string()],
+                ..rule(
+                    Some(r"^/old/(.*)$"),
+                    RewriteAction::Redirect {
+                        location: "https://www.example.com/$1".to_string(),
+                        status: RedirectStatus(StatusCode::PERMANENT_REDIRECT),
+                    },
+                )
+            },
+            RewriteRule {
+                query: HashMap::from([(
+                    "maintenance".to_string(),
+                    Pattern::try_from("^1$".to_string()).unwrap(),
+                )]),
+                ..rule(
+                    None,
+                    RewriteAction::Respond {
+                        status: RespondStatus(StatusCode::SERVICE_UNAVAILABLE),
+                        body: "maintenance".to_string(),
+                        content_type: None,
+                    },
+                )
+            },
+        ]
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test_case("/blog/hello", &[], "/blog/posts/hello.html"; "named capture")]
+    #[test_case("/docs/intro.html", &[("Accept-Language", "fr-CH, fr;q=0.9")], "/fr/docs/intro.html"; "header condition")]
+    #[test_case("/docs/intro.html", &[("Accept-Language", "en")], "/docs/intro.html"; "header condition not met")]
+    #[test_case("/about", &[], "/about"; "no rule")]
+    fn test_evaluate_rewrite(uri: &str, headers: &[(&str, &str)], expected: &str) {
+        let rewritten = evaluate(&rules(), &request(uri, headers), "foo.example.com");
+        assert_eq!(rewritten.path, expected);
+        assert!(rewritten.outcome.is_none());
+    }
+
+    #[test]
+    fn test_evaluate_headers_after_rewrite() {
+        let rewritten = evaluate(&rules(), &request("/blog/hello", &[]), "foo.example.com");
+        assert_eq!(rewritten.headers["X-Page"], "/blog/posts/hello");
+    }
+
+    #[test]
+    fn test_add_headers() {
+        let mut resp_headers = HeaderMap::from_iter([
+            (VARY, HeaderValue::from_static("Accept-Language")),
+            (
+                hyper::header::CONTENT_TYPE,
+                HeaderValue::from_static("text/html"),
+            ),
+        ]);
+        let headers = HeaderMap::from_iter([
+            (VARY, HeaderValue::from_static("Cookie, accept-language")),
+            (
+                hyper::header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain"),
+            ),
+            (
+                hyper::header::CACHE_CONTROL,
+                HeaderValue::from_static("no-store"),
+            ),
+        ]);
+        add_headers(headers, &mut resp_headers);
+
+        assert_eq!(
+            resp_headers
+                .get_all(VARY)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect::<Vec<&str>>(),
+            vec!["Accept-Language", "Cookie"]
+        );
+        assert_eq!(resp_headers[hyper::header::CONTENT_TYPE], "text/html");
+        assert_eq!(resp_headers[hyper::header::CACHE_CONTROL], "no-store");
+    }
+
+    #[test_case("/old/a/b", Some(Outcome::Redirect { status: StatusCode::PERMANENT_REDIRECT, location: "https://www.example.com/a/b".to_string() }); "redirect")]
+    #[test_case("/old/a?page=2", Some(Outcome::Redirect { status: StatusCode::PERMANENT_REDIRECT, location: "https://www.example.com/a?page=2".to_string() }); "redirect keeps query")]
+    #[test_case("/index.html?maintenance=1", Some(Outcome::Respond { status: StatusCode::SERVICE_UNAVAILABLE, content_type: mime::TEXT_PLAIN_UTF_8.to_string(), body: "maintenance".to_string() }); "respond")]
+    #[test_case("/index.html?maintenance=0", None; "query condition not met")]
+    fn test_evaluate_outcome(uri: &str, expected: Option<Outcome>) {
+        let rewritten = evaluate(&rules(), &request(uri, &[]), "foo.example.com");
+        assert_eq!(rewritten.outcome, expected);
+    }
+
+    #[test]
+    fn test_evaluate_method_and_host() {
+        let mut req = request("/old/a", &[]);
+        *req.method_mut() = hyper::Method::HEAD;
+        assert!(evaluate(&rules(), &req, "foo.example.com")
+            .outcome
+            .is_none());
+
+        let rules = vec![RewriteRule {
+            host: Some(Pattern::try_from(r"^bar\.".to_string()).unwrap()),
+            ..rule(
+                None,
+                RewriteAction::Rewrite {
+                    path: "/bar/index.html".to_string(),
+                },
+            )
+        }];
+        let req = request("/", &[]);
+        assert_eq!(evaluate(&rules, &req, "foo.example.com").path, "/");
+        assert_eq!(
+            evaluate(&rules, &req, "bar.example.com").path,
+            "/bar/index.html"
+        );
+    }
+}
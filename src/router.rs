@@ -1,10 +1,21 @@
-use crate::s3::S3;
-use crate::{handler, response};
+use crate::client_ip::ClientIp;
+use crate::config::{SignedUrlConfig, SiteConfig};
+use crate::domain::Domains;
+use crate::i18n::{self, Localized};
+use crate::rewrite::{self, Outcome};
+use crate::signature::SignatureError;
+use crate::site::Site;
+use crate::{cors, handler, metrics, response, signature};
 use bytes::Bytes;
 use http_body_util::Full;
 use hyper::body::Incoming;
+use hyper::header::ORIGIN;
 use hyper::{Method, Request, Response, StatusCode};
-use regex::Regex;
+use std::net::SocketAddr;
+
+/// Address of the connected client, attached to each request by the server.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
 
 #[derive(Debug, thiserror::Error)]
 pub enum RouterError {
@@ -14,71 +25,242 @@ pub enum RouterError {
     Handler(#[from] handler::HandlerError),
 }
 
-pub async fn gateway_route<T>(
-    req: Request<Incoming>,
-    s3_client: T,
-    allow_domains: Vec<String>,
-    root_object: Option<String>,
-    subdir_root_object: Option<String>,
-    no_such_key_redirect_object: Option<String>,
-    self_account_id: Option<String>,
-) -> Result<Response<Full<Bytes>>, RouterError>
-where
-    T: S3 + Send + Sync + 'static,
-{
-    let host = match req.headers().get("Host") {
-        Some(header) => {
-            let value = header
-                .to_str()
-                .unwrap_or_default()
-                .split(':')
-                .collect::<Vec<&str>>()[0];
-
-            if value.is_empty() {
-                return Ok(response::easy_response(StatusCode::BAD_REQUEST)?);
+/// Routes a request to `site`, the site of its host found by [`find_site`].
+pub async fn gateway_route<B>(
+    req: Request<B>,
+    allow_domains: &Domains,
+    site: &Site,
+) -> Result<Response<Full<Bytes>>, RouterError> {
+    let accept = req
+        .headers()
+        .get("Accept")
+        .and_then(|value| value.to_str().ok());
+
+    let Some(host) = host(&req) else {
+        return Ok(response::error_response(StatusCode::BAD_REQUEST, accept)?);
+    };
+
+    if !allow_domains.matches(host) {
+        return Ok(response::error_response(StatusCode::FORBIDDEN, accept)?);
+    }
+
+    let client_ip = req.extensions().get::<ClientIp>().map(|ClientIp(ip)| *ip);
+    if !site.config.allows_ip(client_ip) {
+        tracing::info!(
+            "rejected client address: {}",
+            client_ip.map_or("unknown".to_string(), |ip| ip.to_string())
+        );
+        return Ok(response::error_response(StatusCode::FORBIDDEN, accept)?);
+    }
+
+    let Some(ref cors) = site.cors else {
+        return site_route(&req, site, host, accept).await;
+    };
+    // Bucket rules are only read for requests that may cause S3 requests anyway: preflights carry no
+    // credentials, so on sites requiring them they are answered from the rules authenticated requests read.
+    let mut resp = if cors::is_preflight(req.method(), req.headers()) {
+        let rules = match site.authenticates() {
+            true => cors.cached_rules(host),
+            false => cors.rules(site, host).await,
+        };
+        match cors::preflight_headers(&rules, req.headers()) {
+            Some(headers) => response::preflight_response(headers)?,
+            None => {
+                tracing::info!("rejected cors preflight: {}", req.uri().path());
+                response::error_response(StatusCode::FORBIDDEN, accept)?
             }
+        }
+    } else {
+        let rejection = authenticate(&req, site, accept).await?;
+        let rules = match (req.headers().contains_key(ORIGIN), &rejection) {
+            (false, _) => Default::default(),
+            (true, Some(_)) => cors.cached_rules(host),
+            (true, None) => cors.rules(site, host).await,
+        };
+        let mut resp = match rejection {
+            Some(resp) => resp,
+            None => serve(&req, site, host, accept).await?,
+        };
+        resp.headers_mut()
+            .extend(cors::response_headers(&rules, req.method(), req.headers()));
+        resp
+    };
+    // Responses differ by origin, so caches must not share them across origins.
+    response::append_vary(resp.headers_mut(), "Origin");
+    Ok(resp)
+}
 
-            value
+/// Authenticates the request and serves the site's object for it.
+async fn site_route<B>(
+    req: &Request<B>,
+    site: &Site,
+    host: &str,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, RouterError> {
+    match authenticate(req, site, accept).await? {
+        Some(rejection) => Ok(rejection),
+        None => serve(req, site, host, accept).await,
+    }
+}
+
+/// Checks the signed URL, JWT and Basic credentials the site requires. Returns the response rejecting the request.
+async fn authenticate<B>(
+    req: &Request<B>,
+    site: &Site,
+    accept: Option<&str>,
+) -> Result<Option<Response<Full<Bytes>>>, RouterError> {
+    if let Some(ref signed_urls) = site.config.signed_urls {
+        if let Err(e) = verify_signed_url(req, signed_urls) {
+            tracing::info!("rejected signed url: {}: {}", req.uri().path(), e);
+            return Ok(Some(response::error_response(
+                StatusCode::FORBIDDEN,
+                accept,
+            )?));
+        }
+    }
+
+    if let Some(ref jwt) = site.jwt {
+        if let Err(e) = jwt.authenticate(req.headers()).await {
+            tracing::info!("rejected jwt: {}: {}", req.uri().path(), e);
+            return match e.status() {
+                StatusCode::UNAUTHORIZED => Ok(Some(response::unauthorized_response(
+                    e.challenge(),
+                    accept,
+                )?)),
+                status => Ok(Some(response::error_response(status, accept)?)),
+            };
+        }
+    }
+
+    if let Some(ref basic_auth) = site.basic_auth {
+        if let Err(e) = basic_auth.authenticate(req.headers()).await {
+            tracing::info!("rejected basic auth: {}: {}", req.uri().path(), e);
+            return Ok(Some(response::unauthorized_response(
+                basic_auth.challenge(),
+                accept,
+            )?));
         }
-        None => return Ok(response::easy_response(StatusCode::BAD_REQUEST)?),
+    }
+    Ok(None)
+}
+
+/// Redirects, rewrites and serves the site's object for an authenticated request.
+async fn serve<B>(
+    req: &Request<B>,
+    site: &Site,
+    host: &str,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, RouterError> {
+    if site.config.clean_urls {
+        if let Some(location) = clean_url_location(req, &site.config) {
+            tracing::info!("clean url redirect: {} -> {}", req.uri().path(), location);
+            return Ok(response::redirect_response(
+                StatusCode::MOVED_PERMANENTLY,
+                &location,
+            )?);
+        }
+    }
+
+    let rewritten = rewrite::evaluate(&site.config.rewrites, req, host);
+    let mut resp = match rewritten.outcome {
+        Some(Outcome::Redirect { status, location }) => {
+            tracing::info!("redirect: {} -> {}", req.uri().path(), location);
+            response::redirect_response(status, &location)?
+        }
+        Some(Outcome::Respond {
+            status,
+            content_type,
+            body,
+        }) => response::fixed_response(status, &content_type, body)?,
+        None => localized_route(req, site, host, &rewritten.path, accept).await?,
     };
+    rewrite::add_headers(rewritten.headers, resp.headers_mut());
+    Ok(resp)
+}
 
-    let domain_check = match is_allow_domain(allow_domains, host) {
-        Ok(result) => result,
-        Err(_) => return Ok(response::easy_response(StatusCode::INTERNAL_SERVER_ERROR)?),
+/// Serves the path in the locale of the request when the site is localized.
+async fn localized_route<B>(
+    req: &Request<B>,
+    site: &Site,
+    host: &str,
+    path: &str,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, RouterError> {
+    let Some(ref i18n) = site.config.i18n else {
+        return object_route(req, site, host, path, None, accept).await;
     };
-    if !domain_check {
-        return Ok(response::easy_response(StatusCode::FORBIDDEN)?);
+
+    let mut resp = match i18n::localize(i18n, req, path) {
+        Localized::Serve {
+            locale,
+            path,
+            negotiated: false,
+        } => return object_route(req, site, host, &path, Some(locale), accept).await,
+        Localized::Serve { locale, path, .. } => {
+            object_route(req, site, host, &path, Some(locale), accept).await?
+        }
+        Localized::Redirect(location) => {
+            tracing::info!("locale redirect: {} -> {}", req.uri().path(), location);
+            response::redirect_response(StatusCode::FOUND, &location)?
+        }
+    };
+    for name in i18n::vary(i18n) {
+        response::append_vary(resp.headers_mut(), name);
     }
+    Ok(resp)
+}
+
+/// Resolves the key of a path, after rewrites, and serves its object. Keys of a locale are under its prefix.
+async fn object_route<B>(
+    req: &Request<B>,
+    site: &Site,
+    host: &str,
+    path: &str,
+    locale: Option<&str>,
+    accept: Option<&str>,
+) -> Result<Response<Full<Bytes>>, RouterError> {
+    let is_navigation = is_navigation(path, accept);
+    let config = &site.config;
+    let locale_key = |key: &str| match locale {
+        Some(locale) if !key.is_empty() => format!("{}/{}", locale, key),
+        _ => key.to_string(),
+    };
+    // With clean URLs, `/about` is also tried as `about.html` when the key resolved below misses.
+    let clean_url_key = (config.clean_urls && !path.ends_with('/') && !has_extension(path))
+        .then(|| locale_key(&format!("{}.html", path.trim_start_matches('/'))));
 
-    let mut path = req.uri().path().to_string();
-    if let Some(ref root) = root_object {
+    let mut path = path.to_string();
+    if let Some(ref root) = config.root_object {
         if path == "/" {
             path.push_str(root)
         }
     }
-    if let Some(ref subdir_root) = subdir_root_object {
+    if let Some(ref subdir_root) = config.subdir_root_object {
         if path.ends_with('/') || !path.contains('.') {
             path.push('/');
             path.push_str(subdir_root);
         }
     }
-    let key = path.trim_start_matches('/');
+    let key = &locale_key(path.trim_start_matches('/'));
 
     if key.is_empty() {
-        return Ok(response::easy_response(StatusCode::NOT_FOUND)?);
+        return Ok(handler::s3_not_found(site, host, accept).await?);
     }
 
     match req.method() {
         &Method::GET => Ok(handler::s3_handle(
-            &s3_client,
-            no_such_key_redirect_object,
-            self_account_id,
+            site,
             host,
             key,
+            clean_url_key.as_deref(),
+            accept,
+            is_navigation,
         )
         .await?),
-        _ => Ok(response::easy_response(StatusCode::METHOD_NOT_ALLOWED)?),
+        _ => Ok(response::error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            accept,
+        )?),
     }
 }
 
@@ -87,60 +269,253 @@ pub async fn management_route(
 ) -> Result<Response<Full<Bytes>>, RouterError> {
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/health") => Ok(response::easy_response(StatusCode::OK)?),
+        (&Method::GET, "/metrics") => Ok(response::metrics_response(metrics::render())?),
         _ => Ok(response::easy_response(StatusCode::NOT_FOUND)?),
     }
 }
 
-fn is_allow_domain(allow_domains: Vec<String>, domain: &str) -> Result<bool, regex::Error> {
-    let re = Regex::new(r"^(\*\.)?([a-zA-Z0-9]+(-[a-zA-Z0-9]+)*\.)+[a-zA-Z]{2,}$")?;
-    let mut domain_regex: Vec<Regex> = Vec::new();
-    for domain in allow_domains.iter().filter(|domain| re.is_match(domain)) {
-        let domain = domain.replace('*', r"([a-zA-Z0-9]+(-[a-zA-Z0-9]+)*)");
-        let domain = domain.replace('.', r"\.");
-        if let Ok(re) = Regex::new(&format!("^{}$", domain)) {
-            domain_regex.push(re)
-        };
+/// The requested host without the port. `None` when the `Host` header is missing or empty.
+pub fn host<B>(req: &Request<B>) -> Option<&str> {
+    let value = req
+        .headers()
+        .get("Host")?
+        .to_str()
+        .unwrap_or_default()
+        .split(':')
+        .collect::<Vec<&str>>()[0];
+
+    (!value.is_empty()).then_some(value)
+}
+
+/// Whether the request looks like a browser navigation: it asks for HTML and the last path segment has no extension.
+fn is_navigation(path: &str, accept: Option<&str>) -> bool {
+    let accepts_html = accept
+        .map(response::quality_values)
+        .unwrap_or_default()
+        .iter()
+        .any(|(media_range, q)| media_range == mime::TEXT_HTML.as_ref() && *q > 0.0);
+
+    accepts_html && !has_extension(path)
+}
+
+/// Whether the last path segment has an extension.
+fn has_extension(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .is_some_and(|segment| segment.contains('.'))
+}
+
+/// The canonical location of a request for an `.html` object: without the extension, or the directory
+/// for an index document.
+fn clean_url_location<B>(req: &Request<B>, config: &SiteConfig) -> Option<String> {
+    let path = req.uri().path();
+    let (dir, file) = path.rsplit_once('/')?;
+    let stem = file.strip_suffix(".html").filter(|stem| !stem.is_empty())?;
+
+    let is_index = |object: &Option<String>| object.as_deref() == Some(file);
+    let mut location = if (dir.is_empty() && is_index(&config.root_object))
+        || is_index(&config.subdir_root_object)
+    {
+        format!("{}/", dir)
+    } else {
+        format!("{}/{}", dir, stem)
+    };
+    if let Some(query) = req.uri().query() {
+        location.push('?');
+        location.push_str(query);
     }
+    Some(location)
+}
+
+fn verify_signed_url<B>(req: &Request<B>, config: &SignedUrlConfig) -> Result<(), SignatureError> {
+    let cookie = req
+        .headers()
+        .get("Cookie")
+        .and_then(|value| value.to_str().ok());
+    let token = signature::find_token(req.uri().query(), cookie).ok_or(SignatureError::Missing)?;
+    let keys = config
+        .keys
+        .iter()
+        .map(|key| (key.id.as_str(), key.secret.expose().as_bytes()));
+    let client_ip = req.extensions().get::<ClientIp>().map(|ClientIp(ip)| *ip);
 
-    Ok(domain_regex.iter().any(|re| re.is_match(domain)))
+    signature::verify(token, keys, req.uri().path(), client_ip).map(|_| ())
+}
+
+/// The first site with a domain matching the host.
+pub fn find_site<'a>(sites: &'a [Site], host: &str) -> Option<&'a Site> {
+    sites.iter().find(|site| site.domains.matches(host))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{
+        I18nConfig, I18nMode, Pattern, ResponseHeaderName, RewriteAction, RewriteRule,
+    };
+    use crate::memory::Memory;
+    use crate::storage::SharedStorage;
+    use hyper::header::{CONTENT_TYPE, LOCATION, VARY};
+    use std::collections::HashMap;
+    use std::sync::Arc;
     use test_case::test_case;
 
-    #[test_case(vec!["foo.example.com"], "foo.example.com"; "exact match")]
-    #[test_case(vec!["*.example.com"], "foo.example.com"; "wildcard match")]
-    #[test_case(vec!["*.bar.example.com"], "foo.bar.example.com"; "wildcard match with subdomain")]
-    fn test_is_allow_domain_true(allow_domains: Vec<&str>, domain: &str) {
-        let allow_domains = allow_domains
-            .iter()
-            .map(|domain| domain.to_string())
-            .collect::<Vec<String>>();
-        assert!(is_allow_domain(allow_domains, domain).unwrap());
-    }
-
-    #[test_case(vec!["foo.example.com"], "bar.example.com"; "exact match")]
-    #[test_case(vec!["*.example.com"], "bar.example.net"; "wildcard match")]
-    #[test_case(vec!["*.example.com"], "foo.bar.example.net"; "wildcard match with nested subdomain")]
-    #[test_case(vec!["*.example.com"], "example.com"; "wildcard match with root domain")]
-    fn test_is_allow_domain_false(allow_domains: Vec<&str>, domain: &str) {
-        let allow_domains = allow_domains
-            .iter()
-            .map(|domain| domain.to_string())
-            .collect::<Vec<String>>();
-        assert!(!is_allow_domain(allow_domains, domain).unwrap());
-    }
-
-    #[test_case(vec!["*example.com"], "foo.example.com"; "invalid wildcard match")]
-    #[test_case(vec!["*.*.example.com"], "foo.bar.example.com"; "invalid wildcard match with nested subdomain")]
-    #[test_case(vec!["hoge.example.*"], "foo.example.net"; "invalid wildcard match with top level domain")]
-    fn test_is_allow_domain_invalid(allow_domains: Vec<&str>, domain: &str) {
-        let allow_domains = allow_domains
-            .iter()
-            .map(|domain| domain.to_string())
-            .collect::<Vec<String>>();
-        assert!(!is_allow_domain(allow_domains, domain).unwrap());
+    #[test_case("/", Some("text/html"), true; "root")]
+    #[test_case("/users/1", Some("text/html,application/xhtml+xml,*/*;q=0.8"), true; "client route")]
+    #[test_case("/app.js", Some("text/html"), false; "file extension")]
+    #[test_case("/users/1", Some("*/*"), false; "no html")]
+    #[test_case("/users/1", Some("text/html;q=0"), false; "html refused")]
+    #[test_case("/users/1", None, false; "no accept header")]
+    fn test_is_navigation(path: &str, accept: Option<&str>, expected: bool) {
+        assert_eq!(is_navigation(path, accept), expected);
+    }
+
+    #[test_case("/about.html", Some("/about"); "html object")]
+    #[test_case("/docs/intro.html?v=2", Some("/docs/intro?v=2"); "query kept")]
+    #[test_case("/index.html", Some("/"); "root object")]
+    #[test_case("/docs/index.html", Some("/docs/"); "subdir root object")]
+    #[test_case("/about", None; "clean url")]
+    #[test_case("/app.js", None; "other extension")]
+    #[test_case("/.html", None; "no stem")]
+    fn test_clean_url_location(uri: &str, expected: Option<&str>) {
+        let config = SiteConfig {
+            root_object: Some("index.html".to_string()),
+            subdir_root_object: Some("index.html".to_string()),
+            clean_urls: true,
+            ..Default::default()
+        };
+        let req = Request::builder().uri(uri).body(()).unwrap();
+        assert_eq!(clean_url_location(&req, &config).as_deref(), expected);
+    }
+
+    #[test_case("foo.example.com", Some(0); "first site")]
+    #[test_case("bar.example.com", Some(1); "wildcard site")]
+    #[test_case("foo.example.net", None; "no site")]
+    fn test_find_site(domain: &str, expected: Option<usize>) {
+        let storage: SharedStorage = Arc::new(Memory::default());
+        let sites = vec![
+            Site::new(
+                SiteConfig {
+                    domains: vec!["foo.example.com".to_string()],
+                    ..Default::default()
+                },
+                storage.clone(),
+                None,
+            ),
+            Site::new(
+                SiteConfig {
+                    domains: vec!["*.example.com".to_string()],
+                    ..Default::default()
+                },
+                storage.clone(),
+                None,
+            ),
+        ];
+        let site = find_site(&sites, domain);
+        assert_eq!(
+            site.map(|site| site as *const Site),
+            expected.map(|i| &sites[i] as *const Site)
+        );
+    }
+
+    /// A site localized in `en` and `ja`, with `ja/index.html` stored.
+    fn localized_site(rewrites: Vec<(&str, RewriteAction)>, mode: I18nMode) -> Site {
+        let storage = Memory::default();
+        storage.insert("foo.example.com", "ja/index.html", "<h1>ja</h1>", None);
+        let rewrites = rewrites
+            .into_iter()
+            .map(|(path, action)| RewriteRule {
+                path: Some(Pattern::try_from(path.to_string()).unwrap()),
+                host: None,
+                methods: Vec::new(),
+                headers: HashMap::new(),
+                query: HashMap::new(),
+                action,
+            })
+            .collect();
+        Site::new(
+            SiteConfig {
+                root_object: Some("index.html".to_string()),
+                rewrites,
+                i18n: Some(I18nConfig {
+                    locales: vec!["en".to_string(), "ja".to_string()],
+                    default_locale: "en".to_string(),
+                    mode,
+                    cookie: None,
+                    query: None,
+                }),
+                ..Default::default()
+            },
+            Arc::new(storage),
+            None,
+        )
+    }
+
+    async fn route_ja(site: Site, uri: &str) -> Response<Full<Bytes>> {
+        let req = Request::builder()
+            .uri(uri)
+            .header("Host", "foo.example.com")
+            .header("Accept-Language", "ja")
+            .body(())
+            .unwrap();
+        gateway_route(req, &Domains::new(&["foo.example.com".to_string()]), &site)
+            .await
+            .unwrap()
+    }
+
+    #[test_case("/home?page=2", StatusCode::FOUND, Some("/ja/home?page=2"); "locale redirect of a rewritten path")]
+    #[test_case("/ja/home", StatusCode::OK, None; "rewritten path of a locale")]
+    #[tokio::test]
+    async fn test_gateway_route_rewrite_locale_redirect(
+        uri: &str,
+        status: StatusCode,
+        location: Option<&str>,
+    ) {
+        let site = localized_site(
+            vec![(
+                "^(/ja)?/home$",
+                RewriteAction::Rewrite {
+                    path: "/index.html".to_string(),
+                },
+            )],
+            I18nMode::Redirect,
+        );
+
+        let resp = route_ja(site, uri).await;
+        assert_eq!(resp.status(), status);
+        assert_eq!(
+            resp.headers()
+                .get(LOCATION)
+                .map(|value| value.to_str().unwrap()),
+            location
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gateway_route_header_rule_vary() {
+        let site = localized_site(
+            vec![(
+                "^/",
+                RewriteAction::Headers {
+                    headers: HashMap::from([
+                        (ResponseHeaderName(VARY), "Cookie".to_string()),
+                        (ResponseHeaderName(CONTENT_TYPE), "text/plain".to_string()),
+                    ]),
+                },
+            )],
+            I18nMode::Rewrite,
+        );
+
+        let resp = route_ja(site, "/").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get_all(VARY)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect::<Vec<&str>>(),
+            vec!["Accept-Language", "Cookie"]
+        );
+        assert_eq!(resp.headers()[CONTENT_TYPE], mime::TEXT_HTML.as_ref());
     }
 }
@@ -1,86 +1,238 @@
-use aws_sdk_s3::error::SdkError;
-use aws_sdk_s3::operation::get_object::GetObjectError;
-use aws_sdk_s3::operation::head_bucket::HeadBucketError;
-use aws_sdk_s3::operation::head_object::HeadObjectError;
-use aws_smithy_types::byte_stream::ByteStream;
-
-#[derive(Debug)]
-pub struct GetObjectResult {
-    body: ByteStream,
-}
-
-impl GetObjectResult {
-    pub fn body(self) -> ByteStream {
-        self.body
-    }
-}
-
-#[async_trait::async_trait]
-pub trait S3 {
-    async fn get_object(
-        &self,
-        bucket: &str,
-        key: &str,
-    ) -> Result<GetObjectResult, SdkError<GetObjectError>>;
+use crate::config::{CorsRule, S3StorageConfig};
+use crate::storage::{GetObjectResult, HeadObjectResult, Storage, StorageError};
+use aws_config::meta::region::ProvideRegion;
+use aws_config::profile::{ProfileFileCredentialsProvider, ProfileFileRegionProvider};
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::Region;
+use aws_credential_types::provider::SharedCredentialsProvider;
+use aws_credential_types::Credentials;
+use aws_sdk_s3::config::IdentityCache;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_smithy_runtime_api::client::orchestrator::HttpResponse;
+use aws_smithy_types::date_time::Format;
+use aws_types::request_id::RequestId;
+use std::time::Duration;
 
-    async fn head_object(&self, bucket: &str, key: &str) -> Result<(), SdkError<HeadObjectError>>;
-
-    async fn head_bucket(
-        &self,
-        bucket: &str,
-        expected_bucket_owner: &str,
-    ) -> Result<(), SdkError<HeadBucketError>>;
-}
+/// How long before expiry assumed role credentials are refreshed.
+const ROLE_CREDENTIALS_REFRESH_BUFFER: Duration = Duration::from_secs(5 * 60);
 
-#[cfg(not(feature = "__tests"))]
 #[derive(Debug, Clone)]
 pub struct Client {
     inner: aws_sdk_s3::Client,
 }
 
-#[cfg(not(feature = "__tests"))]
 impl Client {
-    pub async fn new() -> Self {
+    /// Builds a client from the shared AWS config with the endpoint, region and credentials in `settings` applied.
+    /// Static credentials take precedence over a profile. With a role, those credentials are used to assume it
+    /// through the STS endpoint `sts_endpoint_url`, or the default one.
+    pub async fn new(
+        aws_config: &aws_types::SdkConfig,
+        settings: &S3StorageConfig,
+        sts_endpoint_url: Option<&str>,
+    ) -> Self {
+        let mut builder = aws_sdk_s3::config::Builder::from(aws_config);
+        if let Some(ref endpoint_url) = settings.endpoint_url {
+            builder = builder.endpoint_url(endpoint_url);
+        }
+        if let Some(force_path_style) = settings.force_path_style {
+            builder = builder.force_path_style(force_path_style);
+        }
+        let mut credentials = None;
+        if let Some(ref profile) = settings.profile {
+            credentials = Some(SharedCredentialsProvider::new(
+                ProfileFileCredentialsProvider::builder()
+                    .profile_name(profile)
+                    .build(),
+            ));
+            if settings.region.is_none() {
+                let region = ProfileFileRegionProvider::builder()
+                    .profile_name(profile)
+                    .build()
+                    .region()
+                    .await;
+                if let Some(region) = region {
+                    builder = builder.region(region);
+                }
+            }
+        }
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (&settings.access_key_id, &settings.secret_access_key)
+        {
+            credentials = Some(SharedCredentialsProvider::new(Credentials::new(
+                access_key_id,
+                secret_access_key.expose(),
+                settings
+                    .session_token
+                    .as_ref()
+                    .map(|token| token.expose().to_string()),
+                None,
+                "storage-gateway",
+            )));
+        }
+        if let Some(ref region) = settings.region {
+            builder = builder.region(Region::new(region.clone()));
+        }
+        if let Some(ref role_arn) = settings.role_arn {
+            let mut sts_config = aws_config.clone().into_builder();
+            if let Some(endpoint_url) = sts_endpoint_url {
+                sts_config = sts_config.endpoint_url(endpoint_url);
+            }
+            let mut provider = AssumeRoleProvider::builder(role_arn).configure(&sts_config.build());
+            if let Some(ref external_id) = settings.external_id {
+                provider = provider.external_id(external_id);
+            }
+            if let Some(ref session_name) = settings.role_session_name {
+                provider = provider.session_name(session_name);
+            }
+            let provider = match credentials {
+                Some(base) => provider.build_from_provider(base).await,
+                None => provider.build().await,
+            };
+            credentials = Some(SharedCredentialsProvider::new(provider));
+            builder = builder.identity_cache(
+                IdentityCache::lazy()
+                    .buffer_time(ROLE_CREDENTIALS_REFRESH_BUFFER)
+                    .build(),
+            );
+        }
+        if let Some(credentials) = credentials {
+            builder = builder.credentials_provider(credentials);
+        }
+
+        Self::from_conf(builder.build())
+    }
+
+    pub fn from_conf(config: aws_sdk_s3::Config) -> Self {
         Self {
-            inner: aws_sdk_s3::Client::new(
-                &aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await,
-            ),
+            inner: aws_sdk_s3::Client::from_conf(config),
         }
     }
 }
 
-#[cfg(not(feature = "__tests"))]
-#[async_trait::async_trait]
-impl S3 for Client {
-    async fn get_object(
+impl Client {
+    async fn get(
         &self,
         bucket: &str,
         key: &str,
-    ) -> Result<GetObjectResult, SdkError<GetObjectError>> {
-        self.inner
+        version_id: Option<&str>,
+        expected_bucket_owner: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        let output = match self
+            .inner
             .get_object()
             .bucket(bucket)
             .key(key)
+            .set_version_id(version_id.map(str::to_string))
+            .set_expected_bucket_owner(expected_bucket_owner.map(str::to_string))
             .send()
             .await
-            .map(|output| GetObjectResult { body: output.body })
+        {
+            Ok(output) => output,
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|error| error.is_no_such_key()) =>
+            {
+                return Err(StorageError::NoSuchKey)
+            }
+            Err(e) => return Err(storage_error(e)),
+        };
+
+        let body = output
+            .body
+            .collect()
+            .await
+            .map_err(|e| StorageError::Upstream(format!("failed to collect body: {}", e)))?;
+
+        let expires = output
+            .expires
+            .and_then(|expires| expires.fmt(Format::HttpDate).ok());
+
+        Ok(GetObjectResult::builder()
+            .body(body.into_bytes())
+            .website_redirect_location(output.website_redirect_location)
+            .cache_control(output.cache_control)
+            .expires(expires)
+            .build())
     }
 
-    async fn head_object(&self, bucket: &str, key: &str) -> Result<(), SdkError<HeadObjectError>> {
-        self.inner
+    async fn head(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expected_bucket_owner: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        match self
+            .inner
             .head_object()
             .bucket(bucket)
             .key(key)
+            .set_version_id(version_id.map(str::to_string))
+            .set_expected_bucket_owner(expected_bucket_owner.map(str::to_string))
             .send()
             .await
-            .map(|_| ())
+        {
+            Ok(output) => Ok(HeadObjectResult::builder()
+                .website_redirect_location(output.website_redirect_location)
+                .build()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|error| error.is_not_found()) =>
+            {
+                Err(StorageError::NoSuchKey)
+            }
+            Err(e) => Err(storage_error(e)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for Client {
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        self.get(bucket, key, version_id, None).await
+    }
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        self.head(bucket, key, version_id, None).await
+    }
+
+    async fn get_object_owned_by(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expected_bucket_owner: &str,
+    ) -> Result<GetObjectResult, StorageError> {
+        self.get(bucket, key, version_id, Some(expected_bucket_owner))
+            .await
+    }
+
+    async fn head_object_owned_by(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        expected_bucket_owner: &str,
+    ) -> Result<HeadObjectResult, StorageError> {
+        self.head(bucket, key, version_id, Some(expected_bucket_owner))
+            .await
     }
 
     async fn head_bucket(
         &self,
         bucket: &str,
         expected_bucket_owner: &str,
-    ) -> Result<(), SdkError<HeadBucketError>> {
+    ) -> Result<(), StorageError> {
         self.inner
             .head_bucket()
             .bucket(bucket)
@@ -88,20 +240,107 @@ impl S3 for Client {
             .send()
             .await
             .map(|_| ())
+            .map_err(storage_error)
+    }
+
+    async fn get_bucket_cors(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: Option<&str>,
+    ) -> Result<Vec<CorsRule>, StorageError> {
+        let output = match self
+            .inner
+            .get_bucket_cors()
+            .bucket(bucket)
+            .set_expected_bucket_owner(expected_bucket_owner.map(str::to_string))
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(e)
+                if e.as_service_error().and_then(ProvideErrorMetadata::code)
+                    == Some("NoSuchCORSConfiguration") =>
+            {
+                return Ok(Vec::new())
+            }
+            Err(e) => return Err(storage_error(e)),
+        };
+
+        // S3 allows credentials for every origin but `*`.
+        Ok(output
+            .cors_rules()
+            .iter()
+            .map(|rule| CorsRule {
+                allowed_origins: rule.allowed_origins().to_vec(),
+                allowed_methods: rule.allowed_methods().to_vec(),
+                allowed_headers: rule.allowed_headers().to_vec(),
+                expose_headers: rule.expose_headers().to_vec(),
+                max_age_secs: rule
+                    .max_age_seconds()
+                    .and_then(|secs| u64::try_from(secs).ok()),
+                allow_credentials: !rule.allowed_origins().iter().any(|origin| origin == "*"),
+            })
+            .collect())
     }
 }
 
-#[cfg(feature = "__tests")]
-#[derive(Debug, thiserror::Error)]
-pub enum MockError {
-    #[error("bucket owner is different: {0}")]
-    BucketOwner(String),
+fn storage_error<E>(error: SdkError<E, HttpResponse>) -> StorageError
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    let message = format!(
+        "request_id: {} e: {}",
+        error.request_id().unwrap_or("-"),
+        DisplayErrorContext(&error)
+    );
+
+    match &error {
+        SdkError::ConstructionFailure(_) => StorageError::Internal(message),
+        SdkError::TimeoutError(_) => StorageError::Timeout(message),
+        SdkError::DispatchFailure(e) if e.is_timeout() => StorageError::Timeout(message),
+        SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            StorageError::Upstream(message)
+        }
+        SdkError::ServiceError(e) => {
+            service_error(error.code(), e.raw().status().as_u16(), message)
+        }
+        _ => StorageError::Internal(message),
+    }
+}
+
+fn service_error(code: Option<&str>, raw_status: u16, message: String) -> StorageError {
+    match code {
+        Some("NoSuchKey") => StorageError::NoSuchKey,
+        Some("NoSuchBucket" | "NoSuchVersion" | "NotFound") => StorageError::NotFound(message),
+        Some(
+            "AccessDenied" | "AllAccessDisabled" | "AccountProblem" | "InvalidObjectState"
+            | "Forbidden",
+        ) => StorageError::AccessDenied(message),
+        Some(
+            "SlowDown"
+            | "Throttling"
+            | "ThrottlingException"
+            | "RequestLimitExceeded"
+            | "TooManyRequests"
+            | "ServiceUnavailable",
+        ) => StorageError::Throttled(message),
+        Some("RequestTimeout") => StorageError::Timeout(message),
+        Some("InternalError") => StorageError::Upstream(message),
+        // HeadObject and HeadBucket errors have no body, so only the status code is available.
+        _ => match raw_status {
+            403 => StorageError::AccessDenied(message),
+            404 => StorageError::NotFound(message),
+            429 | 503 => StorageError::Throttled(message),
+            500..=599 => StorageError::Upstream(message),
+            _ => StorageError::Internal(message),
+        },
+    }
 }
 
 #[cfg(feature = "__tests")]
 #[derive(Debug, Clone)]
 pub struct Mock {
-    inner_client: aws_sdk_s3::Client,
+    inner_client: Client,
     expected_bucket_owner: Vec<(String, String)>,
 }
 
@@ -109,7 +348,7 @@ pub struct Mock {
 impl Mock {
     pub fn new(config: aws_sdk_s3::Config, expected_bucket_owner: Vec<(String, String)>) -> Self {
         Self {
-            inner_client: aws_sdk_s3::Client::from_conf(config),
+            inner_client: Client::from_conf(config),
             expected_bucket_owner,
         }
     }
@@ -117,36 +356,30 @@ impl Mock {
 
 #[cfg(feature = "__tests")]
 #[async_trait::async_trait]
-impl S3 for Mock {
+impl Storage for Mock {
     async fn get_object(
         &self,
         bucket: &str,
         key: &str,
-    ) -> Result<GetObjectResult, SdkError<GetObjectError>> {
-        self.inner_client
-            .get_object()
-            .bucket(bucket)
-            .key(key)
-            .send()
-            .await
-            .map(|output| GetObjectResult { body: output.body })
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        self.inner_client.get_object(bucket, key, version_id).await
     }
 
-    async fn head_object(&self, bucket: &str, key: &str) -> Result<(), SdkError<HeadObjectError>> {
-        self.inner_client
-            .head_object()
-            .bucket(bucket)
-            .key(key)
-            .send()
-            .await
-            .map(|_| ())
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        self.inner_client.head_object(bucket, key, version_id).await
     }
 
     async fn head_bucket(
         &self,
         bucket: &str,
         expected_bucket_owner: &str,
-    ) -> Result<(), SdkError<HeadBucketError>> {
+    ) -> Result<(), StorageError> {
         let result = self
             .expected_bucket_owner
             .iter()
@@ -155,20 +388,83 @@ impl S3 for Mock {
         if result {
             Ok(())
         } else {
-            let source = HeadBucketError::unhandled(MockError::BucketOwner(
-                expected_bucket_owner.to_string(),
-            ));
-            let raw = aws_smithy_runtime_api::http::Response::new(
-                aws_smithy_runtime_api::http::StatusCode::try_from(403).unwrap(),
-                aws_smithy_types::body::SdkBody::empty(),
-            );
-
-            Err(SdkError::ServiceError(
-                aws_smithy_runtime_api::client::result::ServiceError::builder()
-                    .source(source)
-                    .raw(raw)
-                    .build(),
-            ))
+            Err(StorageError::AccessDenied(format!(
+                "bucket owner is different: {}",
+                expected_bucket_owner
+            )))
         }
     }
+
+    async fn get_bucket_cors(
+        &self,
+        bucket: &str,
+        _expected_bucket_owner: Option<&str>,
+    ) -> Result<Vec<CorsRule>, StorageError> {
+        self.inner_client.get_bucket_cors(bucket, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_s3::operation::get_object::GetObjectError;
+    use aws_sdk_s3::operation::head_bucket::HeadBucketError;
+    use aws_smithy_runtime_api::client::result::ConnectorError;
+    use aws_smithy_types::body::SdkBody;
+    use aws_smithy_types::error::ErrorMetadata;
+    use test_case::test_case;
+
+    fn raw_response(status: u16) -> HttpResponse {
+        HttpResponse::new(status.try_into().unwrap(), SdkBody::empty())
+    }
+
+    fn get_object_error(code: &str, status: u16) -> SdkError<GetObjectError, HttpResponse> {
+        SdkError::service_error(
+            GetObjectError::generic(ErrorMetadata::builder().code(code).build()),
+            raw_response(status),
+        )
+    }
+
+    #[test_case("NoSuchKey", 404, "no such key"; "no such key code")]
+    #[test_case("AccessDenied", 403, "access denied"; "access denied code")]
+    #[test_case("NoSuchBucket", 404, "not found"; "no such bucket code")]
+    #[test_case("SlowDown", 503, "throttled"; "slow down code")]
+    #[test_case("RequestTimeout", 400, "timed out"; "request timeout code")]
+    #[test_case("InternalError", 500, "upstream failure"; "internal error code")]
+    #[test_case("Unknown", 429, "throttled"; "unknown code with 429")]
+    #[test_case("Unknown", 400, "internal error"; "unknown code with 400")]
+    fn test_storage_error_service_error(code: &str, status: u16, expected: &str) {
+        let error = storage_error(get_object_error(code, status));
+        assert!(error.to_string().starts_with(expected), "{}", error);
+    }
+
+    #[test_case(403, "access denied"; "forbidden")]
+    #[test_case(404, "not found"; "not found")]
+    #[test_case(503, "throttled"; "service unavailable")]
+    fn test_storage_error_without_body(status: u16, expected: &str) {
+        let error = storage_error(SdkError::service_error(
+            HeadBucketError::generic(ErrorMetadata::builder().build()),
+            raw_response(status),
+        ));
+        assert!(error.to_string().starts_with(expected), "{}", error);
+    }
+
+    #[test]
+    fn test_storage_error_timeout() {
+        let error = storage_error::<GetObjectError>(SdkError::timeout_error("timed out"));
+        assert!(matches!(error, StorageError::Timeout(_)));
+
+        let error = storage_error::<GetObjectError>(SdkError::dispatch_failure(
+            ConnectorError::timeout("connect timed out".into()),
+        ));
+        assert!(matches!(error, StorageError::Timeout(_)));
+    }
+
+    #[test]
+    fn test_storage_error_dispatch_failure() {
+        let error = storage_error::<GetObjectError>(SdkError::dispatch_failure(
+            ConnectorError::io("connection refused".into()),
+        ));
+        assert!(matches!(error, StorageError::Upstream(_)));
+    }
 }
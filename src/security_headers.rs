@@ -0,0 +1,130 @@
+use crate::config::SecurityHeadersConfig;
+use crate::response::GuessedContentType;
+use hyper::header::{
+    HeaderName, HeaderValue, REFERRER_POLICY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use hyper::Response;
+
+const NOSNIFF: HeaderValue = HeaderValue::from_static("nosniff");
+
+/// Headers sent unless a site turns `defaults` off or replaces them. They do not restrict what a page may load,
+/// so policies such as `Content-Security-Policy` and `Strict-Transport-Security` are left to each site.
+const DEFAULTS: [(HeaderName, &str); 3] = [
+    (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    (REFERRER_POLICY, "strict-origin-when-cross-origin"),
+    (X_FRAME_OPTIONS, "SAMEORIGIN"),
+];
+
+/// Applies a site's header policy to one of its responses. Responses whose `Content-Type` was guessed
+/// from the key keep `X-Content-Type-Options: nosniff` whatever the policy says.
+pub fn apply<B>(config: &SecurityHeadersConfig, resp: &mut Response<B>) {
+    let guessed = resp.extensions().get::<GuessedContentType>().is_some();
+    let headers = resp.headers_mut();
+
+    if config.defaults {
+        for (name, value) in DEFAULTS {
+            if !headers.contains_key(&name) {
+                headers.insert(name, HeaderValue::from_static(value));
+            }
+        }
+    }
+    for (name, value) in &config.add.0 {
+        if !headers.contains_key(name) {
+            headers.insert(name, value.clone());
+        }
+    }
+    for (name, value) in &config.set.0 {
+        headers.insert(name, value.clone());
+    }
+    for name in &config.remove {
+        headers.remove(&name.0);
+    }
+
+    if guessed {
+        headers.insert(X_CONTENT_TYPE_OPTIONS, NOSNIFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ResponseHeaderName, ResponseHeaders};
+    use hyper::header::{CONTENT_SECURITY_POLICY, STRICT_TRANSPORT_SECURITY};
+    use std::collections::HashMap;
+    use test_case::test_case;
+
+    fn headers(headers: &[(&str, &str)]) -> ResponseHeaders {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<String, String>>()
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply_defaults() {
+        let mut resp = Response::new(());
+        resp.headers_mut()
+            .insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        apply(&SecurityHeadersConfig::default(), &mut resp);
+
+        assert_eq!(resp.headers().len(), DEFAULTS.len());
+        assert_eq!(resp.headers()[X_FRAME_OPTIONS], "DENY");
+        assert_eq!(resp.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert!(!resp.headers().contains_key(CONTENT_SECURITY_POLICY));
+        assert!(!resp.headers().contains_key(STRICT_TRANSPORT_SECURITY));
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let config = SecurityHeadersConfig {
+            defaults: true,
+            add: headers(&[("X-Robots-Tag", "noindex"), ("X-Frame-Options", "DENY")]),
+            set: headers(&[
+                (
+                    "Content-Security-Policy",
+                    "default-src 'self' cdn.example.com",
+                ),
+                ("Strict-Transport-Security", "max-age=63072000"),
+            ]),
+            remove: vec![ResponseHeaderName(REFERRER_POLICY)],
+        };
+        let mut resp = Response::new(());
+        apply(&config, &mut resp);
+
+        assert_eq!(resp.headers()["X-Robots-Tag"], "noindex");
+        assert_eq!(resp.headers()[X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(
+            resp.headers()[CONTENT_SECURITY_POLICY],
+            "default-src 'self' cdn.example.com"
+        );
+        assert_eq!(
+            resp.headers()[STRICT_TRANSPORT_SECURITY],
+            "max-age=63072000"
+        );
+        assert!(!resp.headers().contains_key(REFERRER_POLICY));
+    }
+
+    #[test_case(false, None; "known content type")]
+    #[test_case(true, Some("nosniff"); "guessed content type")]
+    fn test_apply_nosniff(guessed: bool, expected: Option<&str>) {
+        let config = SecurityHeadersConfig {
+            defaults: false,
+            remove: vec![ResponseHeaderName(X_CONTENT_TYPE_OPTIONS)],
+            ..Default::default()
+        };
+        let mut resp = Response::new(());
+        if guessed {
+            resp.extensions_mut().insert(GuessedContentType);
+        }
+        apply(&config, &mut resp);
+
+        assert_eq!(
+            resp.headers()
+                .get(X_CONTENT_TYPE_OPTIONS)
+                .map(|value| value.to_str().unwrap()),
+            expected
+        );
+    }
+}
@@ -1,23 +1,35 @@
-use crate::{s3, service};
-use aws_config::BehaviorVersion;
+use crate::bucket_owner::BucketOwnerCheck;
+use crate::concurrency::HostLimiter;
+use crate::config::{
+    AwsClientConfig, BucketOwnerCacheConfig, Cidr, ConcurrencyConfig, ConnectionConfig,
+    ForwardedHeader, SiteConfig,
+};
+use crate::domain::Domains;
+use crate::proxy_protocol::{self, ProxiedAddr};
+use crate::response::ResponseError;
+use crate::router::PeerAddr;
+use crate::site::{Site, StorageFactory};
+use crate::{aws, connection, htpasswd, release, service};
 #[cfg(feature = "__tests")]
 use aws_config::Region;
 #[cfg(feature = "__tests")]
 use aws_credential_types::Credentials;
 use aws_sdk_sts::operation::get_caller_identity::GetCallerIdentityError;
-#[cfg(feature = "__tests")]
-use aws_types::sdk_config::SharedCredentialsProvider;
 use bytes::Bytes;
 use http_body_util::Full;
 use hyper::body::Incoming;
-use hyper::server::conn::http1;
-use hyper::service::Service;
+use hyper::service::{service_fn, Service};
 use hyper::{Request, Response};
-use hyper_util::rt::TokioIo;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 use typed_builder::TypedBuilder;
 
+/// Time a connection has to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, thiserror::Error)]
 pub enum ServerError {
     #[error("failed to bind to address: {0}")]
@@ -25,7 +37,7 @@ pub enum ServerError {
     #[error("failed to accept connection: {0}")]
     Accept(std::io::Error),
     #[error("failed to get self account id: {0}")]
-    GetSelfAccountId(#[from] aws_sdk_sts::error::SdkError<GetCallerIdentityError>),
+    GetSelfAccountId(#[from] Box<aws_sdk_sts::error::SdkError<GetCallerIdentityError>>),
 }
 
 #[derive(TypedBuilder)]
@@ -36,19 +48,57 @@ pub struct GatewayServer {
     addr: SocketAddr,
     allow_domains: Vec<String>,
     #[builder(default)]
-    root_object: Option<String>,
+    default_site: SiteConfig,
     #[builder(default)]
-    subdir_root_object: Option<String>,
-    #[builder(default)]
-    no_such_key_redirect_object: Option<String>,
+    sites: Vec<SiteConfig>,
     #[builder(default)]
     allow_cross_account: bool,
+    #[builder(default)]
+    aws_client: AwsClientConfig,
+    #[builder(default)]
+    bucket_owner_cache: BucketOwnerCacheConfig,
+    #[builder(default)]
+    request_timeout: Option<Duration>,
+    #[builder(default)]
+    trusted_proxies: Vec<Cidr>,
+    #[builder(default)]
+    proxy_protocol: bool,
+    #[builder(default)]
+    concurrency: ConcurrencyConfig,
+    #[builder(default)]
+    connection: ConnectionConfig,
+    #[builder(default)]
+    forwarded_header: ForwardedHeader,
 }
 
-impl<T, U> GatewayServerBuilder<((SocketAddr,), (Vec<String>,), T, T, T, U)>
+impl<S, T, U, V, W, X, Y, Z, A, B, C>
+    GatewayServerBuilder<(
+        (SocketAddr,),
+        (Vec<String>,),
+        S,
+        T,
+        U,
+        V,
+        W,
+        X,
+        Y,
+        Z,
+        A,
+        B,
+        C,
+    )>
 where
-    T: typed_builder::Optional<Option<String>>,
+    S: typed_builder::Optional<SiteConfig>,
+    T: typed_builder::Optional<Vec<SiteConfig>>,
     U: typed_builder::Optional<bool>,
+    V: typed_builder::Optional<AwsClientConfig>,
+    W: typed_builder::Optional<BucketOwnerCacheConfig>,
+    X: typed_builder::Optional<Option<Duration>>,
+    Y: typed_builder::Optional<Vec<Cidr>>,
+    Z: typed_builder::Optional<bool>,
+    A: typed_builder::Optional<ConcurrencyConfig>,
+    B: typed_builder::Optional<ConnectionConfig>,
+    C: typed_builder::Optional<ForwardedHeader>,
 {
     pub async fn build(self) -> Result<(), ServerError> {
         let input = self.__build();
@@ -57,56 +107,91 @@ where
             .await
             .map_err(ServerError::Bind)?;
 
-        #[cfg(not(feature = "__tests"))]
-        let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        let loader = aws::config_loader(&input.aws_client);
         #[cfg(feature = "__tests")]
-        let aws_config = aws_config::SdkConfig::builder()
-            .credentials_provider(SharedCredentialsProvider::new(Credentials::new(
+        let loader = loader
+            .credentials_provider(Credentials::new(
                 "012345678901",
                 "dummy",
                 None,
                 None,
                 "tests",
-            )))
+            ))
             .region(Region::new("us-east-1"))
-            .endpoint_url("http://172.17.0.1:4566")
-            .behavior_version(BehaviorVersion::latest())
-            .build();
+            .endpoint_url("http://172.17.0.1:4566");
+        let aws_config = loader.load().await;
 
-        let self_account_id = if !input.allow_cross_account {
-            let sts_client = aws_sdk_sts::Client::from_conf(aws_sdk_sts::Config::from(&aws_config));
-            let resp = sts_client.get_caller_identity().send().await?;
+        // The account id is only needed to check the owner of AWS S3 buckets read with the gateway's own identity.
+        let needs_self_account_id = std::iter::once(&input.default_site)
+            .chain(&input.sites)
+            .any(SiteConfig::needs_self_account_id);
+        let self_account_id = if !input.allow_cross_account && needs_self_account_id {
+            let mut sts_config = aws_sdk_sts::config::Builder::from(&aws_config);
+            if let Some(ref endpoint_url) = input.aws_client.sts_endpoint_url {
+                sts_config = sts_config.endpoint_url(endpoint_url);
+            }
+            let sts_client = aws_sdk_sts::Client::from_conf(sts_config.build());
+            let resp = sts_client
+                .get_caller_identity()
+                .send()
+                .await
+                .map_err(Box::new)?;
             resp.account
         } else {
             None
         };
 
-        #[cfg(not(feature = "__tests"))]
-        let s3_client = s3::Client::new().await;
-        #[cfg(feature = "__tests")]
-        let s3_client = {
-            let config = aws_sdk_s3::config::Builder::from(&aws_config)
-                .force_path_style(true)
-                .build();
-            s3::Mock::new(
-                config,
-                vec![
-                    ("foo.example.com".to_string(), "012345678901".to_string()),
-                    ("bar.example.net".to_string(), "012345678901".to_string()),
-                    ("foobar.example.com".to_string(), "123456789012".to_string()),
-                ],
-            )
+        let mut storages = StorageFactory::new(
+            aws_config,
+            input.aws_client.sts_endpoint_url,
+            &input.concurrency,
+        );
+        let self_account_id = self_account_id.as_deref();
+        let bucket_owner = |config: &SiteConfig| {
+            let owners = config.expected_bucket_owners(self_account_id, input.allow_cross_account);
+            (!owners.is_empty()).then(|| BucketOwnerCheck::new(owners, input.bucket_owner_cache))
         };
+        let mut sites = Vec::new();
+        for site in input.sites {
+            let storage = storages
+                .storage(&site.storage.clone().unwrap_or_default())
+                .await;
+            let bucket_owner = bucket_owner(&site);
+            sites.push(Site::new(site, storage, bucket_owner));
+        }
+        let storage = storages
+            .storage(&input.default_site.storage.clone().unwrap_or_default())
+            .await;
+        let default_bucket_owner = bucket_owner(&input.default_site);
+        let default_site = Site::new(input.default_site, storage, default_bucket_owner);
+
+        for site in sites.iter().chain([&default_site]) {
+            release::spawn_refresh(site.clone());
+            if let Some(ref basic_auth) = site.basic_auth {
+                htpasswd::spawn_reload(basic_auth.clone());
+            }
+        }
 
-        let svc = service::GatewayService::builder()
-            .s3_client(s3_client)
-            .allow_domains(input.allow_domains)
-            .root_object(input.root_object)
-            .subdir_root_object(input.subdir_root_object)
-            .no_such_key_redirect_object(input.no_such_key_redirect_object)
-            .self_account_id(self_account_id)
-            .build();
-        serve(listener, svc).await
+        let svc =
+            service::GatewayService::builder()
+                .allow_domains(Arc::new(Domains::new(&input.allow_domains)))
+                .default_site(Arc::new(default_site))
+                .sites(Arc::new(sites))
+                .request_timeout(input.request_timeout)
+                .trusted_proxies(Arc::new(input.trusted_proxies))
+                .forwarded_header(input.forwarded_header)
+                .host_limiter(input.concurrency.max_in_flight_per_host.map(|limit| {
+                    Arc::new(HostLimiter::new(limit, input.concurrency.load_shed_wait))
+                }))
+                .build();
+        serve(
+            listener,
+            svc,
+            input.proxy_protocol,
+            input.concurrency.max_connections,
+            input.connection,
+        )
+        .await
     }
 }
 
@@ -116,9 +201,17 @@ where
 )]
 pub struct ManagementServer {
     addr: SocketAddr,
+    #[builder(default)]
+    proxy_protocol: bool,
+    #[builder(default)]
+    connection: ConnectionConfig,
 }
 
-impl ManagementServerBuilder<((SocketAddr,),)> {
+impl<S, T> ManagementServerBuilder<((SocketAddr,), S, T)>
+where
+    S: typed_builder::Optional<bool>,
+    T: typed_builder::Optional<ConnectionConfig>,
+{
     pub async fn build(self) -> Result<(), ServerError> {
         let input = self.__build();
 
@@ -127,23 +220,64 @@ impl ManagementServerBuilder<((SocketAddr,),)> {
             .map_err(ServerError::Bind)?;
 
         let svc = service::ManagementService;
-        serve(listener, svc).await
+        serve(listener, svc, input.proxy_protocol, None, input.connection).await
     }
 }
 
-async fn serve<S>(listener: TcpListener, svc: S) -> Result<(), ServerError>
+/// Accepts connections, reading a PROXY protocol header first from each one when `proxy_protocol` is set.
+/// With `max_connections`, accepting pauses while that many connections are open.
+async fn serve<S>(
+    listener: TcpListener,
+    svc: S,
+    proxy_protocol: bool,
+    max_connections: Option<usize>,
+    connection: ConnectionConfig,
+) -> Result<(), ServerError>
 where
     S: Service<Request<Incoming>, Response = Response<Full<Bytes>>> + Clone + Send + Sync + 'static,
-    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
-    S::Future: Send,
+    S::Error: From<ResponseError> + Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    S::Future: Send + 'static,
 {
+    let connections = max_connections
+        .map(|connections| Arc::new(Semaphore::new(connections.min(Semaphore::MAX_PERMITS))));
     loop {
-        let (stream, _) = listener.accept().await.map_err(ServerError::Accept)?;
-        let io = TokioIo::new(stream);
+        let permit = match connections {
+            Some(ref connections) => connections.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+        let (mut stream, peer_addr) = listener.accept().await.map_err(ServerError::Accept)?;
         let svc = svc.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = http1::Builder::new().serve_connection(io, svc).await {
+            let _permit = permit;
+            let proxied_addr = if proxy_protocol {
+                let header = proxy_protocol::read_header(&mut stream);
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                    Ok(Ok(addr)) => addr,
+                    Ok(Err(e)) => {
+                        tracing::warn!("rejected connection from {}: {}", peer_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::warn!(
+                            "timed out reading proxy protocol header from {}",
+                            peer_addr
+                        );
+                        return;
+                    }
+                }
+            } else {
+                None
+            };
+
+            let svc = service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(PeerAddr(peer_addr));
+                if let Some(addr) = proxied_addr {
+                    req.extensions_mut().insert(ProxiedAddr(addr));
+                }
+                svc.call(req)
+            });
+            if let Err(e) = connection::serve(stream, svc, &connection).await {
                 if e.is_closed()
                     || e.is_parse()
                     || e.is_parse_too_large()
@@ -1,59 +1,145 @@
-use crate::router;
-use crate::s3::S3;
+use crate::client_ip::{self, ClientIp};
+use crate::concurrency::HostLimiter;
+use crate::config::{Cidr, ForwardedHeader};
+use crate::domain::Domains;
+use crate::proxy_protocol::ProxiedAddr;
+use crate::router::PeerAddr;
+use crate::site::Site;
+use crate::{response, router, security_headers};
 use bytes::Bytes;
 use http_body_util::Full;
 use hyper::body::Incoming;
+use hyper::header::ACCEPT;
 use hyper::service::Service;
-use hyper::{Request, Response};
+use hyper::{Request, Response, StatusCode};
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
 use typed_builder::TypedBuilder;
 
+/// Seconds clients are asked to wait before retrying a shed request.
+const RETRY_AFTER_SECS: u64 = 1;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ServiceError {
     #[error("failed to route: {0}")]
     Router(#[from] router::RouterError),
+    #[error("failed to respond: {0}")]
+    Response(#[from] response::ResponseError),
 }
 
 #[derive(Debug, Clone, TypedBuilder)]
-pub struct GatewayService<T> {
-    s3_client: T,
-    allow_domains: Vec<String>,
-    root_object: Option<String>,
-    subdir_root_object: Option<String>,
-    no_such_key_redirect_object: Option<String>,
-    self_account_id: Option<String>,
+pub struct GatewayService {
+    allow_domains: Arc<Domains>,
+    default_site: Arc<Site>,
+    sites: Arc<Vec<Site>>,
+    request_timeout: Option<Duration>,
+    trusted_proxies: Arc<Vec<Cidr>>,
+    forwarded_header: ForwardedHeader,
+    host_limiter: Option<Arc<HostLimiter>>,
 }
 
-impl<T> Service<Request<Incoming>> for GatewayService<T>
-where
-    T: S3 + Clone + Send + Sync + 'static,
-{
+impl Service<Request<Incoming>> for GatewayService {
     type Response = Response<Full<Bytes>>;
     type Error = ServiceError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let s3_client = self.s3_client.clone();
-        let allow_domains = self.allow_domains.clone();
-        let default_root = self.root_object.clone();
-        let default_subdir_root = self.subdir_root_object.clone();
-        let no_such_key_redirect = self.no_such_key_redirect_object.clone();
-        let self_account_id = self.self_account_id.clone();
-
-        Box::pin(async move {
-            router::gateway_route(
-                req,
-                s3_client,
-                allow_domains,
-                default_root,
-                default_subdir_root,
-                no_such_key_redirect,
-                self_account_id,
+    fn call(&self, mut req: Request<Incoming>) -> Self::Future {
+        let proxied = req
+            .extensions()
+            .get::<ProxiedAddr>()
+            .map(|ProxiedAddr(addr)| addr.ip());
+        let client_ip = req.extensions().get::<PeerAddr>().map(|PeerAddr(addr)| {
+            client_ip::resolve(
+                addr.ip(),
+                proxied,
+                req.headers(),
+                &self.trusted_proxies,
+                self.forwarded_header,
             )
-            .await
-            .map_err(ServiceError::Router)
-        })
+        });
+        let span = match client_ip {
+            Some(ip) => {
+                req.extensions_mut().insert(ClientIp(ip));
+                tracing::info_span!("request", client_ip = %ip)
+            }
+            None => tracing::info_span!("request"),
+        };
+        let svc = self.clone();
+
+        Box::pin(
+            async move {
+                let site = svc.find_site(&req);
+                let mut resp = svc.handle(req, site, client_ip).await?;
+                security_headers::apply(&site.config.security_headers, &mut resp);
+                Ok(resp)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+impl GatewayService {
+    /// The site of the requested host, which applies its rate limit and response headers and routes the request.
+    fn find_site(&self, req: &Request<Incoming>) -> &Site {
+        router::host(req)
+            .and_then(|host| router::find_site(&self.sites, host))
+            .unwrap_or(&self.default_site)
+    }
+
+    async fn handle(
+        &self,
+        req: Request<Incoming>,
+        site: &Site,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Response<Full<Bytes>>, ServiceError> {
+        let accept = req.headers().get(ACCEPT).cloned();
+        let accept = accept.as_ref().and_then(|value| value.to_str().ok());
+        let host = router::host(&req).map(str::to_string);
+
+        // Limited requests never reach the storage.
+        if let (Some(rate_limiter), Some(host)) = (&site.rate_limiter, &host) {
+            if let Err(retry_after) = rate_limiter.check(req.headers(), host, client_ip) {
+                tracing::info!("rate limited: retry after {}s", retry_after);
+                return Ok(response::retry_after_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    retry_after,
+                    accept,
+                )?);
+            }
+        }
+        let _host_permit = match (&self.host_limiter, &host) {
+            (Some(host_limiter), Some(host)) => match host_limiter.acquire(host).await {
+                Ok(permit) => Some(permit),
+                Err(e) => {
+                    tracing::warn!("shed request: {}: {}", host, e);
+                    return Ok(response::retry_after_response(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        RETRY_AFTER_SECS,
+                        accept,
+                    )?);
+                }
+            },
+            _ => None,
+        };
+        let route = router::gateway_route(req, &self.allow_domains, site);
+
+        let Some(request_timeout) = self.request_timeout else {
+            return route.await.map_err(ServiceError::Router);
+        };
+        match tokio::time::timeout(request_timeout, route).await {
+            Ok(resp) => resp.map_err(ServiceError::Router),
+            Err(_) => {
+                tracing::warn!("request timed out after {:?}", request_timeout);
+                Ok(response::error_response(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    accept,
+                )?)
+            }
+        }
     }
 }
 
@@ -0,0 +1,282 @@
+use crate::response;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Name of the query parameter and of the cookie that carry a token.
+pub const TOKEN_NAME: &str = "gw_token";
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SignatureError {
+    #[error("no token")]
+    Missing,
+    #[error("malformed token")]
+    Malformed,
+    #[error("unknown key: {0}")]
+    UnknownKey(String),
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("token expired at {0}")]
+    Expired(u64),
+    #[error("path is out of the token scope: {0}")]
+    OutOfScope(String),
+    #[error("client ip does not match the token")]
+    IpMismatch,
+    #[error("path prefix contains ';' or '=': {0}")]
+    InvalidPrefix(String),
+}
+
+/// What a token grants: paths under `path_prefix` until `expires`, optionally only from `ip`.
+/// A prefix not ending with `/` covers the path itself and the paths below it, e.g. `/reports` covers
+/// `/reports` and `/reports/2024.pdf` but not `/reports-internal/`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// Unix time in seconds.
+    pub expires: u64,
+    pub path_prefix: String,
+    pub ip: Option<IpAddr>,
+}
+
+impl Policy {
+    /// Fails when the prefix contains the field separators, which would not decode back to the same policy.
+    fn encode(&self) -> Result<String, SignatureError> {
+        if self.path_prefix.contains([';', '=']) {
+            return Err(SignatureError::InvalidPrefix(self.path_prefix.clone()));
+        }
+        let mut policy = format!("exp={};prefix={}", self.expires, self.path_prefix);
+        if let Some(ip) = self.ip {
+            policy.push_str(&format!(";ip={}", ip));
+        }
+        Ok(policy)
+    }
+
+    /// Whether `path` is `path_prefix` or below it.
+    fn covers(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.path_prefix) {
+            Some(rest) => {
+                self.path_prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+            }
+            None => false,
+        }
+    }
+
+    fn decode(policy: &str) -> Result<Self, SignatureError> {
+        let mut expires = None;
+        let mut path_prefix = None;
+        let mut ip = None;
+        for field in policy.split(';') {
+            match field.split_once('=') {
+                Some(("exp", value)) => {
+                    expires = Some(value.parse().map_err(|_| SignatureError::Malformed)?)
+                }
+                Some(("prefix", value)) => path_prefix = Some(value.to_string()),
+                Some(("ip", value)) => {
+                    ip = Some(value.parse().map_err(|_| SignatureError::Malformed)?)
+                }
+                _ => return Err(SignatureError::Malformed),
+            }
+        }
+
+        Ok(Self {
+            expires: expires.ok_or(SignatureError::Malformed)?,
+            path_prefix: path_prefix.ok_or(SignatureError::Malformed)?,
+            ip,
+        })
+    }
+}
+
+/// Returns a token of the form `<key id>.<policy>.<signature>`, where the signature is an
+/// HMAC-SHA256 of `<key id>.<policy>` and both parts are unpadded URL-safe base64.
+pub fn sign(policy: &Policy, key_id: &str, secret: &[u8]) -> Result<String, SignatureError> {
+    let signed = format!("{}.{}", key_id, URL_SAFE_NO_PAD.encode(policy.encode()?));
+    let signature = URL_SAFE_NO_PAD.encode(mac(secret, &signed).finalize().into_bytes());
+    Ok(format!("{}.{}", signed, signature))
+}
+
+/// Checks `token` against the active `keys`, given as key id and secret pairs, for a request of `path` from `client_ip`.
+pub fn verify<'a, K>(
+    token: &str,
+    keys: K,
+    path: &str,
+    client_ip: Option<IpAddr>,
+) -> Result<Policy, SignatureError>
+where
+    K: IntoIterator<Item = (&'a str, &'a [u8])>,
+{
+    let (signed, signature) = token.rsplit_once('.').ok_or(SignatureError::Malformed)?;
+    let (key_id, policy) = signed.split_once('.').ok_or(SignatureError::Malformed)?;
+    let (_, secret) = keys
+        .into_iter()
+        .find(|(id, _)| *id == key_id)
+        .ok_or_else(|| SignatureError::UnknownKey(key_id.to_string()))?;
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| SignatureError::Malformed)?;
+    mac(secret, signed)
+        .verify_slice(&signature)
+        .map_err(|_| SignatureError::InvalidSignature)?;
+
+    let policy = URL_SAFE_NO_PAD
+        .decode(policy)
+        .ok()
+        .and_then(|policy| String::from_utf8(policy).ok())
+        .ok_or(SignatureError::Malformed)?;
+    let policy = Policy::decode(&policy)?;
+
+    if policy.expires <= now() {
+        return Err(SignatureError::Expired(policy.expires));
+    }
+    if !policy.covers(path) {
+        return Err(SignatureError::OutOfScope(path.to_string()));
+    }
+    if policy.ip.is_some() && policy.ip != client_ip {
+        return Err(SignatureError::IpMismatch);
+    }
+
+    Ok(policy)
+}
+
+/// Finds a token in the query string or, failing that, in the `Cookie` header.
+pub fn find_token<'a>(query: Option<&'a str>, cookie: Option<&'a str>) -> Option<&'a str> {
+    let from_query = query.and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == TOKEN_NAME)
+            .map(|(_, value)| value)
+    });
+
+    from_query.or_else(|| cookie.and_then(|cookie| response::cookie_value(cookie, TOKEN_NAME)))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn mac(secret: &[u8], message: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const KEYS: [(&str, &[u8]); 2] = [("2024-01", b"old secret"), ("2024-02", b"new secret")];
+
+    fn policy() -> Policy {
+        Policy {
+            expires: now() + 60,
+            path_prefix: "/private/".to_string(),
+            ip: None,
+        }
+    }
+
+    #[test_case("2024-01"; "previous key")]
+    #[test_case("2024-02"; "current key")]
+    fn test_verify(key_id: &str) {
+        let (_, secret) = KEYS.iter().find(|(id, _)| *id == key_id).unwrap();
+        let token = sign(&policy(), key_id, secret).unwrap();
+        assert_eq!(
+            verify(&token, KEYS, "/private/report.pdf", None),
+            Ok(policy())
+        );
+    }
+
+    #[test]
+    fn test_verify_rejected() {
+        let token = sign(&policy(), "2024-02", b"new secret").unwrap();
+        assert_eq!(
+            verify(&token, KEYS, "/public/index.html", None),
+            Err(SignatureError::OutOfScope("/public/index.html".to_string()))
+        );
+        assert_eq!(
+            verify(
+                &token,
+                [("2024-02", b"other secret".as_slice())],
+                "/private/a",
+                None
+            ),
+            Err(SignatureError::InvalidSignature)
+        );
+        assert_eq!(
+            verify(&token, [KEYS[0]], "/private/a", None),
+            Err(SignatureError::UnknownKey("2024-02".to_string()))
+        );
+        assert_eq!(
+            verify("not a token", KEYS, "/private/a", None),
+            Err(SignatureError::Malformed)
+        );
+
+        let expired = Policy {
+            expires: 1,
+            ..policy()
+        };
+        let token = sign(&expired, "2024-02", b"new secret").unwrap();
+        assert_eq!(
+            verify(&token, KEYS, "/private/a", None),
+            Err(SignatureError::Expired(1))
+        );
+    }
+
+    #[test_case("/reports", "/reports", true; "prefix itself")]
+    #[test_case("/reports", "/reports/2024.pdf", true; "below prefix")]
+    #[test_case("/reports", "/reports-internal/2024.pdf", false; "sibling sharing the prefix")]
+    #[test_case("/reports/", "/reports/2024.pdf", true; "prefix with slash")]
+    #[test_case("/reports/", "/reports", false; "directory without slash")]
+    #[test_case("/", "/index.html", true; "root")]
+    fn test_verify_scope(path_prefix: &str, path: &str, in_scope: bool) {
+        let policy = Policy {
+            path_prefix: path_prefix.to_string(),
+            ..policy()
+        };
+        let token = sign(&policy, "2024-02", b"new secret").unwrap();
+        assert_eq!(verify(&token, KEYS, path, None).is_ok(), in_scope);
+    }
+
+    #[test]
+    fn test_sign_invalid_prefix() {
+        let policy = Policy {
+            path_prefix: "/a;ip=192.0.2.1".to_string(),
+            ..policy()
+        };
+        assert_eq!(
+            sign(&policy, "2024-02", b"new secret"),
+            Err(SignatureError::InvalidPrefix("/a;ip=192.0.2.1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_verify_ip() {
+        let ip = "192.0.2.1".parse().ok();
+        let token = sign(&Policy { ip, ..policy() }, "2024-02", b"new secret").unwrap();
+        assert!(verify(&token, KEYS, "/private/a", ip).is_ok());
+        assert_eq!(
+            verify(&token, KEYS, "/private/a", "192.0.2.2".parse().ok()),
+            Err(SignatureError::IpMismatch)
+        );
+        assert_eq!(
+            verify(&token, KEYS, "/private/a", None),
+            Err(SignatureError::IpMismatch)
+        );
+    }
+
+    #[test_case(Some("a=1&gw_token=abc"), None, Some("abc"); "query")]
+    #[test_case(None, Some("theme=dark; gw_token=def"), Some("def"); "cookie")]
+    #[test_case(Some("gw_token=abc"), Some("gw_token=def"), Some("abc"); "query first")]
+    #[test_case(Some("a=1"), Some("theme=dark"), None; "no token")]
+    fn test_find_token(query: Option<&str>, cookie: Option<&str>, expected: Option<&str>) {
+        assert_eq!(find_token(query, cookie), expected);
+    }
+}
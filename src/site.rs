@@ -0,0 +1,166 @@
+use crate::bucket_owner::BucketOwnerCheck;
+use crate::concurrency::Limited;
+use crate::config::{ConcurrencyConfig, S3StorageConfig, SiteConfig, StorageConfig};
+use crate::cors::Cors;
+use crate::domain::Domains;
+use crate::filesystem::Filesystem;
+use crate::htpasswd::Htpasswd;
+use crate::jwt::JwtAuth;
+use crate::memory::Memory;
+use crate::rate_limit::RateLimiter;
+use crate::release::ReleaseManifests;
+use crate::s3;
+use crate::storage::{SharedStorage, StorageError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// A site's settings together with the storage its objects are served from.
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub config: SiteConfig,
+    /// The site's domains, compiled once.
+    pub domains: Domains,
+    pub storage: SharedStorage,
+    /// Check that the requested bucket belongs to the expected account. `None` skips the check.
+    pub bucket_owner: Option<Arc<BucketOwnerCheck>>,
+    pub release_manifests: Option<Arc<ReleaseManifests>>,
+    pub jwt: Option<Arc<JwtAuth>>,
+    pub basic_auth: Option<Arc<Htpasswd>>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub cors: Option<Arc<Cors>>,
+}
+
+impl Site {
+    pub fn new(
+        config: SiteConfig,
+        storage: SharedStorage,
+        bucket_owner: Option<BucketOwnerCheck>,
+    ) -> Self {
+        let release_manifests = config
+            .release_manifest
+            .clone()
+            .map(|release_manifest| Arc::new(ReleaseManifests::new(release_manifest)));
+        let jwt = config.jwt.clone().map(|jwt| Arc::new(JwtAuth::new(jwt)));
+        let basic_auth = config
+            .basic_auth
+            .clone()
+            .map(|basic_auth| Arc::new(Htpasswd::new(basic_auth)));
+        let rate_limiter = config
+            .rate_limit
+            .clone()
+            .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit)));
+        let cors = config.cors.clone().map(|cors| Arc::new(Cors::new(cors)));
+
+        Self {
+            domains: Domains::new(&config.domains),
+            config,
+            storage,
+            bucket_owner: bucket_owner.map(Arc::new),
+            release_manifests,
+            jwt,
+            basic_auth,
+            rate_limiter,
+            cors,
+        }
+    }
+
+    /// Whether requests must carry a signed URL, a JWT or Basic credentials.
+    pub fn authenticates(&self) -> bool {
+        self.config.signed_urls.is_some() || self.jwt.is_some() || self.basic_auth.is_some()
+    }
+
+    /// The account the bucket belongs to once the bucket owner check passes, or `None` without a check.
+    pub async fn expected_owner(&self, bucket: &str) -> Result<Option<String>, StorageError> {
+        match self.bucket_owner {
+            Some(ref bucket_owner) => Ok(Some(
+                bucket_owner.check(self.storage.as_ref(), bucket).await?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Builds site storages. Sites with the same S3 settings, including the assumed role, share one client.
+/// All S3 clients share the `s3_max_concurrency` slots.
+#[derive(Debug)]
+pub struct StorageFactory {
+    aws_config: aws_types::SdkConfig,
+    sts_endpoint_url: Option<String>,
+    s3_clients: Vec<(S3StorageConfig, SharedStorage)>,
+    s3_slots: Option<Arc<Semaphore>>,
+    load_shed_wait: Duration,
+}
+
+impl StorageFactory {
+    pub fn new(
+        aws_config: aws_types::SdkConfig,
+        sts_endpoint_url: Option<String>,
+        concurrency: &ConcurrencyConfig,
+    ) -> Self {
+        Self {
+            aws_config,
+            sts_endpoint_url,
+            s3_clients: Vec::new(),
+            s3_slots: concurrency
+                .s3_max_concurrency
+                .map(|slots| Arc::new(Semaphore::new(slots.min(Semaphore::MAX_PERMITS)))),
+            load_shed_wait: concurrency.load_shed_wait,
+        }
+    }
+
+    pub async fn storage(&mut self, config: &StorageConfig) -> SharedStorage {
+        match config {
+            StorageConfig::S3(settings) => self.s3_storage(settings).await,
+            StorageConfig::Filesystem { root } => Arc::new(Filesystem::new(root.clone())),
+            StorageConfig::Memory { objects } => {
+                let memory = Memory::default();
+                for object in objects {
+                    memory.insert(
+                        &object.bucket,
+                        &object.key,
+                        object.body.clone(),
+                        object.website_redirect_location.clone(),
+                    );
+                }
+                Arc::new(memory)
+            }
+        }
+    }
+
+    async fn s3_storage(&mut self, settings: &S3StorageConfig) -> SharedStorage {
+        if let Some((_, storage)) = self.s3_clients.iter().find(|(s, _)| s == settings) {
+            return storage.clone();
+        }
+
+        #[cfg(feature = "__tests")]
+        let storage: SharedStorage = if *settings == S3StorageConfig::default() {
+            Arc::new(s3::Mock::new(
+                aws_sdk_s3::config::Builder::from(&self.aws_config)
+                    .force_path_style(true)
+                    .build(),
+                vec![
+                    ("foo.example.com".to_string(), "012345678901".to_string()),
+                    ("bar.example.net".to_string(), "012345678901".to_string()),
+                    ("foobar.example.com".to_string(), "123456789012".to_string()),
+                ],
+            ))
+        } else {
+            Arc::new(
+                s3::Client::new(&self.aws_config, settings, self.sts_endpoint_url.as_deref()).await,
+            )
+        };
+        #[cfg(not(feature = "__tests"))]
+        let storage: SharedStorage = Arc::new(
+            s3::Client::new(&self.aws_config, settings, self.sts_endpoint_url.as_deref()).await,
+        );
+
+        let storage: SharedStorage = match self.s3_slots {
+            Some(ref slots) => Arc::new(Limited::new(storage, slots.clone(), self.load_shed_wait)),
+            None => storage,
+        };
+
+        self.s3_clients.push((settings.clone(), storage.clone()));
+        storage
+    }
+}
@@ -0,0 +1,208 @@
+use crate::config::CorsRule;
+use bytes::Bytes;
+use std::sync::Arc;
+use typed_builder::TypedBuilder;
+
+pub type SharedStorage = Arc<dyn Storage + Send + Sync>;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum StorageError {
+    #[error("no such key")]
+    NoSuchKey,
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("access denied: {0}")]
+    AccessDenied(String),
+    #[error("throttled: {0}")]
+    Throttled(String),
+    #[error("timed out: {0}")]
+    Timeout(String),
+    #[error("upstream failure: {0}")]
+    Upstream(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+#[derive(Debug, TypedBuilder)]
+pub struct GetObjectResult {
+    body: Bytes,
+    #[builder(default)]
+    website_redirect_location: Option<String>,
+    /// The object's `Cache-Control` metadata.
+    #[builder(default)]
+    cache_control: Option<String>,
+    /// The object's `Expires` metadata, as an HTTP date.
+    #[builder(default)]
+    expires: Option<String>,
+}
+
+impl GetObjectResult {
+    pub fn body(self) -> Bytes {
+        self.body
+    }
+
+    pub fn website_redirect_location(&self) -> Option<&str> {
+        self.website_redirect_location.as_deref()
+    }
+
+    pub fn cache_control(&self) -> Option<&str> {
+        self.cache_control.as_deref()
+    }
+
+    pub fn expires(&self) -> Option<&str> {
+        self.expires.as_deref()
+    }
+}
+
+#[derive(Debug, TypedBuilder)]
+pub struct HeadObjectResult {
+    #[builder(default)]
+    website_redirect_location: Option<String>,
+}
+
+impl HeadObjectResult {
+    pub fn website_redirect_location(&self) -> Option<&str> {
+        self.website_redirect_location.as_deref()
+    }
+}
+
+/// Fails for backends that keep a single version of each object when a version is requested.
+pub fn unversioned(version_id: Option<&str>) -> Result<(), StorageError> {
+    match version_id {
+        Some(version_id) => Err(StorageError::NotFound(format!(
+            "object versions are not supported: {}",
+            version_id
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// A source of objects. The bucket is the requested host and the key is the resolved request path.
+#[async_trait::async_trait]
+pub trait Storage: std::fmt::Debug {
+    /// Reads an object, or the given version of it.
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError>;
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError>;
+
+    /// Like `get_object`, but fails unless the bucket belongs to `expected_bucket_owner`.
+    /// Backends without a notion of ownership ignore the owner.
+    async fn get_object_owned_by(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        _expected_bucket_owner: &str,
+    ) -> Result<GetObjectResult, StorageError> {
+        self.get_object(bucket, key, version_id).await
+    }
+
+    /// Like `head_object`, but fails unless the bucket belongs to `expected_bucket_owner`.
+    async fn head_object_owned_by(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        _expected_bucket_owner: &str,
+    ) -> Result<HeadObjectResult, StorageError> {
+        self.head_object(bucket, key, version_id).await
+    }
+
+    /// Checks that the bucket exists and belongs to `expected_bucket_owner`.
+    /// Backends without a notion of ownership only check existence.
+    async fn head_bucket(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: &str,
+    ) -> Result<(), StorageError>;
+
+    /// The CORS rules of the bucket, empty when it has none. Backends without bucket settings have none.
+    async fn get_bucket_cors(
+        &self,
+        _bucket: &str,
+        _expected_bucket_owner: Option<&str>,
+    ) -> Result<Vec<CorsRule>, StorageError> {
+        Ok(Vec::new())
+    }
+}
+
+/// A storage whose object reads are pinned to the bucket owner found by the bucket owner check.
+#[derive(Debug)]
+pub struct ExpectedOwner<'a, T: ?Sized> {
+    storage: &'a T,
+    owner: Option<&'a str>,
+}
+
+impl<'a, T: ?Sized> ExpectedOwner<'a, T> {
+    pub fn new(storage: &'a T, owner: Option<&'a str>) -> Self {
+        Self { storage, owner }
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> Storage for ExpectedOwner<'_, T>
+where
+    T: Storage + Send + Sync + ?Sized,
+{
+    async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<GetObjectResult, StorageError> {
+        match self.owner {
+            Some(owner) => {
+                self.storage
+                    .get_object_owned_by(bucket, key, version_id, owner)
+                    .await
+            }
+            None => self.storage.get_object(bucket, key, version_id).await,
+        }
+    }
+
+    async fn head_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<HeadObjectResult, StorageError> {
+        match self.owner {
+            Some(owner) => {
+                self.storage
+                    .head_object_owned_by(bucket, key, version_id, owner)
+                    .await
+            }
+            None => self.storage.head_object(bucket, key, version_id).await,
+        }
+    }
+
+    async fn head_bucket(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: &str,
+    ) -> Result<(), StorageError> {
+        self.storage
+            .head_bucket(bucket, expected_bucket_owner)
+            .await
+    }
+
+    async fn get_bucket_cors(
+        &self,
+        bucket: &str,
+        expected_bucket_owner: Option<&str>,
+    ) -> Result<Vec<CorsRule>, StorageError> {
+        self.storage
+            .get_bucket_cors(bucket, expected_bucket_owner.or(self.owner))
+            .await
+    }
+}
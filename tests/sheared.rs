@@ -1,6 +1,13 @@
+use aws_credential_types::Credentials;
+use aws_sdk_s3::config::{BehaviorVersion, Region};
+use aws_sdk_s3::primitives::ByteStream;
 use std::collections::HashMap;
 use testcontainers::core::WaitFor;
-use testcontainers::Image;
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, Image, RunnableImage};
+
+pub const MINIO_ACCESS_KEY_ID: &str = "minioadmin";
+pub const MINIO_SECRET_ACCESS_KEY: &str = "minioadmin";
 
 #[derive(Debug)]
 pub struct TestImage {
@@ -60,11 +67,97 @@ impl HttpClient {
     }
 
     pub async fn get(&self, domain: &str, path: &str) -> reqwest::Response {
-        self.inner_client
-            .get(format!("{}{}", self.base_url, path))
-            .header("Host", domain)
+        self.get_with_headers(domain, path, &[]).await
+    }
+
+    pub async fn get_with_headers(
+        &self,
+        domain: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> reqwest::Response {
+        self.request(reqwest::Method::GET, domain, path, headers)
+            .await
+    }
+
+    pub async fn request(
+        &self,
+        method: reqwest::Method,
+        domain: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> reqwest::Response {
+        let mut builder = self
+            .inner_client
+            .request(method, format!("{}{}", self.base_url, path))
+            .header("Host", domain);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.send().await.unwrap()
+    }
+}
+
+/// Starts a MinIO server and puts `objects` into `bucket`. Returns the container and its S3 port.
+pub async fn start_minio(
+    bucket: &str,
+    objects: &[(&str, &str)],
+) -> (ContainerAsync<GenericImage>, u16) {
+    let image = GenericImage::new("minio/minio", "latest")
+        .with_exposed_port(9000)
+        .with_env_var("MINIO_ROOT_USER", MINIO_ACCESS_KEY_ID)
+        .with_env_var("MINIO_ROOT_PASSWORD", MINIO_SECRET_ACCESS_KEY)
+        .with_wait_for(WaitFor::message_on_stdout("API:"));
+    let container = RunnableImage::from((image, vec!["server".to_string(), "/data".to_string()]))
+        .start()
+        .await;
+    let port = container.get_host_port_ipv4(9000).await;
+
+    let config = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .endpoint_url(format!("http://localhost:{}", port))
+        .force_path_style(true)
+        .credentials_provider(Credentials::new(
+            MINIO_ACCESS_KEY_ID,
+            MINIO_SECRET_ACCESS_KEY,
+            None,
+            None,
+            "tests",
+        ))
+        .build();
+    let client = aws_sdk_s3::Client::from_conf(config);
+    client.create_bucket().bucket(bucket).send().await.unwrap();
+    for (key, body) in objects {
+        client
+            .put_object()
+            .bucket(bucket)
+            .key(*key)
+            .body(ByteStream::from(body.as_bytes().to_vec()))
             .send()
             .await
-            .unwrap()
+            .unwrap();
     }
+
+    (container, port)
+}
+
+/// Signs `claims` with the test key `kid`, whose public key is in `tests/config/jwks.json`.
+pub fn jwt(kid: &str, claims: serde_json::Value) -> String {
+    let pem = std::fs::read(format!(
+        "{}/tests/data/jwt/{}.pem",
+        env!("CARGO_MANIFEST_DIR"),
+        kid
+    ))
+    .unwrap();
+    let header = jsonwebtoken::Header {
+        kid: Some(kid.to_string()),
+        ..jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256)
+    };
+    jsonwebtoken::encode(
+        &header,
+        &claims,
+        &jsonwebtoken::EncodingKey::from_ec_pem(&pem).unwrap(),
+    )
+    .unwrap()
 }